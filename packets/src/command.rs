@@ -0,0 +1,62 @@
+use std::fmt::Debug;
+
+use crate::error::DecodeError;
+use crate::header::CommandHeader;
+use crate::payload::CommandPayload;
+
+#[derive(Clone)]
+pub struct Command {
+    pub header: CommandHeader,
+    pub payload: Option<CommandPayload>
+}
+
+impl Command {
+    pub fn new(mut header: CommandHeader, payload: CommandPayload) -> Self {
+        header.size += payload.len();
+        Self {
+            header,
+            payload: Some(payload)
+        }
+    }
+
+    pub fn deserialize(buf: &[u8], offset: usize)
+            -> Result<Self, DecodeError> {
+        let header = CommandHeader::deserialize(buf, offset)?;
+        let start = offset + header.len();
+        let end = offset + header.size as usize;
+
+        let body = buf.get(start..end).ok_or(DecodeError::Truncated {
+            offset: start, field: "command body" })?;
+        let payload = CommandPayload::deserialize(body, header)
+            .map_err(|e| e.shift(start))?;
+        Ok(Self {
+            header,
+            payload: Some(payload)
+        })
+    }
+
+    pub fn serialize(&self) -> Option<Vec<u8>> {
+        if self.payload.is_some() {
+            let mut ret = vec![0; self.len() as usize];
+            ret[..self.header.len()].copy_from_slice(&self.header.serialize());
+            ret[self.header.len()..].copy_from_slice(&*self.payload.as_ref().unwrap().serialize());
+
+            Some(ret)
+        } else { None }
+    }
+
+    pub fn len(&self) -> u32 {
+        self.header.size
+    }
+}
+
+impl Debug for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.payload.is_some() {
+            write!(f, "Cmd[ {:?}, payload: {:x?} ]", self.header,
+            &*self.payload.as_ref().unwrap())
+        } else {
+            write!(f, "Cmd[ {:?} ]", self.header)
+        }
+    }
+}
@@ -0,0 +1,66 @@
+/// The CRC follows the datagram header, before the first command.
+pub const CRC_OFFSET: usize = 12;
+pub const CRC_LEN: usize = 4;
+const POLYNOMIAL: u32 = 0xedb8_8320;
+
+/// Photon's CRC32 of a whole datagram, calculated with the CRC field zeroed.
+/// Unlike the usual CRC32 the result isn't inverted.
+pub fn calculate(buf: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for (i, byte) in buf.iter().enumerate() {
+        let in_crc = (CRC_OFFSET..CRC_OFFSET + CRC_LEN).contains(&i);
+        let byte = if in_crc { 0 } else { *byte };
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                0 => crc >> 1,
+                _ => (crc >> 1) ^ POLYNOMIAL
+            };
+        }
+    }
+    crc
+}
+
+/// Checks the CRC of a datagram that carries one.
+pub fn verify(buf: &[u8]) -> bool {
+    let Some(crc) = buf.get(CRC_OFFSET..CRC_OFFSET + CRC_LEN) else {
+        return false;
+    };
+    u32::from_be_bytes(crc.try_into().unwrap()) == calculate(buf)
+}
+
+pub fn write(buf: &mut [u8]) {
+    let crc = calculate(buf);
+    buf[CRC_OFFSET..CRC_OFFSET + CRC_LEN].copy_from_slice(&crc.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answer() {
+        // The usual CRC32 check value 0xcbf43926, just not inverted.
+        assert_eq!(calculate(b"123456789"), 0x340b_c6d9);
+    }
+
+    #[test]
+    fn crc_field_is_ignored() {
+        let mut buf: Vec<u8> = (0..32).collect();
+        let crc = calculate(&buf);
+        buf[CRC_OFFSET..CRC_OFFSET + CRC_LEN].fill(0xff);
+        assert_eq!(calculate(&buf), crc);
+    }
+
+    #[test]
+    fn written_crcs_verify() {
+        let mut buf: Vec<u8> = (0..32).collect();
+        assert!(!verify(&buf));
+        write(&mut buf);
+        assert!(verify(&buf));
+
+        buf[20] ^= 1;
+        assert!(!verify(&buf));
+        assert!(!verify(&buf[..CRC_OFFSET + 2]));
+    }
+}
@@ -0,0 +1,295 @@
+use std::fmt::Debug;
+
+use crate::command::Command;
+use crate::crc::{self, CRC_LEN};
+use crate::error::DecodeError;
+use crate::header::CommandHeader;
+use crate::reader::Reader;
+use crate::typ::CommandType;
+
+pub const HEADER_LEN: usize = 0xc;
+pub const IV_LEN: usize = 16;
+
+/// What the byte after the peer id says about the rest of the datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AuxillaryProperty {
+    Nothing,
+    Encrypted,
+    Crc
+}
+
+impl Into<u8> for AuxillaryProperty {
+    fn into(self) -> u8 {
+        match self {
+            Self::Nothing => 0,
+            Self::Encrypted => 1,
+            Self::Crc => 204
+        }
+    }
+}
+
+impl TryFrom<u8> for AuxillaryProperty {
+    type Error = DecodeError;
+
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(Self::Nothing),
+            1 => Ok(Self::Encrypted),
+            204 => Ok(Self::Crc),
+            _ => Err(DecodeError::InvalidValue { offset: 2,
+                field: "auxiliary property", value: val as u32 })
+        }
+    }
+}
+
+impl Default for AuxillaryProperty {
+    fn default() -> Self {
+        Self::Nothing
+    }
+}
+
+/// A whole datagram in either direction. Datagrams with a CRC carry it right
+/// after the header, encrypted ones their IV. The commands of an encrypted
+/// datagram are left to whoever holds the key, they're neither decoded nor
+/// serialized here.
+#[derive(Clone, Default)]
+pub struct Datagram {
+    pub peer_id: u16,
+    pub aux_property: AuxillaryProperty,
+    pub cmd_count: u8,
+    pub time: u32,
+    pub challenge: u32,
+    pub crc: Option<u32>,
+    pub iv: Option<[u8; IV_LEN]>,
+    pub cmds: Vec<Command>
+}
+
+impl Datagram {
+    pub fn new(peer_id: u16, time: u32, challenge: u32, cmds: Vec<Command>)
+            -> Self {
+        Self {
+            peer_id,
+            cmd_count: cmds.len().try_into().unwrap(),
+            time,
+            challenge,
+            cmds,
+            ..Default::default()
+        }
+    }
+
+    /// Makes the datagram carry a CRC, it's calculated when serializing.
+    pub fn enable_crc(&mut self) {
+        self.aux_property = AuxillaryProperty::Crc;
+    }
+
+    /// Flags the datagram as encrypted under `iv`.
+    pub fn enable_encryption(&mut self, iv: [u8; IV_LEN]) {
+        self.aux_property = AuxillaryProperty::Encrypted;
+        self.iv = Some(iv);
+    }
+
+    pub fn is_encrypted(buf: &[u8]) -> bool {
+        buf.get(2) == Some(&AuxillaryProperty::Encrypted.into())
+    }
+
+    pub fn has_crc(buf: &[u8]) -> bool {
+        buf.get(2) == Some(&AuxillaryProperty::Crc.into())
+    }
+
+    /// Checks the CRC of datagrams that carry one.
+    pub fn crc_matches(buf: &[u8]) -> bool {
+        !Self::has_crc(buf) || crc::verify(buf)
+    }
+
+    fn cmds_offset(aux_property: AuxillaryProperty) -> usize {
+        match aux_property {
+            AuxillaryProperty::Nothing => HEADER_LEN,
+            AuxillaryProperty::Encrypted => HEADER_LEN + IV_LEN,
+            AuxillaryProperty::Crc => HEADER_LEN + CRC_LEN
+        }
+    }
+
+    /// Checks that the datagram holds its header and every command it
+    /// announces in full.
+    pub fn is_complete(buf: &[u8]) -> bool {
+        let aux_property = buf.get(2).and_then(|x|
+            AuxillaryProperty::try_from(*x).ok()).unwrap_or_default();
+        let mut offset = Self::cmds_offset(aux_property);
+        if buf.len() < offset {
+            return false;
+        }
+
+        let mut cmd_count = 0;
+        while offset < buf.len() {
+            if buf.len() - offset < HEADER_LEN {
+                return false;
+            }
+            // Unknown types are reported when decoding, not as truncation.
+            let header = CommandHeader {
+                cmd_type: CommandType::try_from(buf[offset])
+                    .unwrap_or_default(),
+                ..Default::default()
+            };
+            let size = u32::from_be_bytes(buf[offset+0x4..offset+0x8]
+                .try_into().unwrap()) as usize;
+            if size < header.len() || size > buf.len() - offset {
+                return false;
+            }
+            offset += size;
+            cmd_count += 1;
+        }
+
+        cmd_count == buf[3] as usize
+    }
+
+    /// Decodes the commands of an encrypted datagram once they're decrypted,
+    /// `buf` holds nothing but the commands.
+    pub fn decode_cmds(&mut self, buf: &[u8]) -> Result<(), DecodeError> {
+        let mut offset = 0;
+        while offset < buf.len() {
+            let cmd = Command::deserialize(buf, offset)?;
+            offset += cmd.len() as usize;
+            self.cmds.push(cmd);
+        }
+        Ok(())
+    }
+
+    /// The commands as they go on the wire, without the datagram header.
+    pub fn serialize_cmds(&self) -> Vec<u8> {
+        let mut ret = Vec::new();
+        for cmd in &self.cmds {
+            match cmd.serialize() {
+                Some(bytes) => ret.extend_from_slice(&bytes),
+                None => ret.resize(ret.len() + cmd.len() as usize, 0)
+            }
+        }
+        ret
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = vec![0; Self::cmds_offset(self.aux_property)];
+        ret[0x0..0x2].copy_from_slice(&self.peer_id.to_be_bytes());
+        ret[0x2] = self.aux_property.into();
+        ret[0x3] = self.cmd_count;
+        ret[0x4..0x8].copy_from_slice(&self.time.to_be_bytes());
+        ret[0x8..0xc].copy_from_slice(&self.challenge.to_be_bytes());
+
+        match self.aux_property {
+            AuxillaryProperty::Encrypted => {
+                let iv = self.iv.unwrap_or_default();
+                ret[HEADER_LEN..].copy_from_slice(&iv);
+            },
+            AuxillaryProperty::Crc => {
+                ret.extend_from_slice(&self.serialize_cmds());
+                crc::write(&mut ret);
+            },
+            AuxillaryProperty::Nothing => {
+                ret.extend_from_slice(&self.serialize_cmds());
+            }
+        }
+        ret
+    }
+}
+
+impl TryFrom<&[u8]> for Datagram {
+    type Error = DecodeError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        let mut r = Reader::new(buf);
+        let peer_id = r.u16("peer id")?;
+        let aux_property = AuxillaryProperty::try_from(r.u8("flags")?)?;
+        let mut ret = Self {
+            peer_id,
+            aux_property,
+            cmd_count: r.u8("command count")?,
+            time: r.u32("time")?,
+            challenge: r.u32("challenge")?,
+            ..Default::default()
+        };
+
+        match aux_property {
+            AuxillaryProperty::Encrypted => {
+                ret.iv = Some(r.array("iv")?);
+                return Ok(ret);
+            },
+            AuxillaryProperty::Crc => {
+                ret.crc = Some(r.u32("crc")?);
+            },
+            AuxillaryProperty::Nothing => {}
+        }
+
+        let offset = r.pos();
+        ret.decode_cmds(&buf[offset..]).map_err(|e| e.shift(offset))?;
+        Ok(ret)
+    }
+}
+
+impl Debug for Datagram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Datagram[ peer_id: 0x{:04x}, aux_property: {:?}, \
+            cmd_count: 0x{:x}, time: 0x{:08x}, challenge: 0x{:08x}",
+            self.peer_id, self.aux_property, self.cmd_count, self.time,
+            self.challenge)?;
+        if let Some(iv) = self.iv {
+            write!(f, ", iv: {:x?}", iv)?;
+        }
+        if let Some(crc) = self.crc {
+            write!(f, ", crc: 0x{:08x}", crc)?;
+        }
+        write!(f, ", cmds: {:?} ]", self.cmds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::CommandPayload;
+
+    /// The datagrams of a capture that Wireshark exported as C arrays, which
+    /// is what `test_packets/convert.py` reads as well.
+    fn captured(conv: &str) -> Vec<Vec<u8>> {
+        let mut ret = Vec::new();
+        let mut datagram = Vec::new();
+        for line in conv.lines() {
+            if line.contains("peer") {
+                continue;
+            }
+            let bytes = line.split(|c: char| !c.is_ascii_alphanumeric())
+                .filter_map(|x| x.strip_prefix("0x"))
+                .map(|x| u8::from_str_radix(x, 16).unwrap());
+            datagram.extend(bytes);
+            if line.contains('}') {
+                ret.push(std::mem::take(&mut datagram));
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn captured_datagrams_round_trip() {
+        let datagrams: Vec<_> = [
+            include_str!("../../test_packets/blub.conv"),
+            include_str!("../../test_packets/blub2.conv")
+        ].into_iter().flat_map(captured).collect();
+        assert_eq!(datagrams.len(), 94);
+
+        for buf in datagrams {
+            let datagram = Datagram::try_from(&*buf).unwrap();
+            assert_eq!(datagram.serialize(), buf, "{:?}", datagram);
+
+            // The commands keep the bytes they came with, so encode their
+            // messages again to check the values as well.
+            let payloads = datagram.cmds.iter().filter_map(|x| x.payload.as_ref());
+            for payload in payloads {
+                let msg = match payload {
+                    CommandPayload::Reliable(x) => x.payload(),
+                    CommandPayload::Unreliable(x) => x.payload(),
+                    _ => continue
+                };
+                assert_eq!(msg.serialize().unwrap(), payload.serialize(),
+                    "{:?}", msg);
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+/// Why a packet couldn't be decoded. Every variant carries the offset into
+/// the decoded buffer and the name of the field it happened at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer ended before the field did.
+    Truncated { offset: usize, field: &'static str },
+    /// The field holds a value that isn't defined for it.
+    InvalidValue { offset: usize, field: &'static str, value: u32 },
+    /// The field is a string that isn't valid UTF-8.
+    InvalidUtf8 { offset: usize, field: &'static str },
+    /// The field is well-formed, but decoding it isn't implemented yet.
+    Unsupported { offset: usize, field: &'static str }
+}
+
+impl DecodeError {
+    pub fn offset(&self) -> usize {
+        match self {
+            Self::Truncated { offset, .. } |
+            Self::InvalidValue { offset, .. } |
+            Self::InvalidUtf8 { offset, .. } |
+            Self::Unsupported { offset, .. } => *offset
+        }
+    }
+
+    pub fn field(&self) -> &'static str {
+        match self {
+            Self::Truncated { field, .. } |
+            Self::InvalidValue { field, .. } |
+            Self::InvalidUtf8 { field, .. } |
+            Self::Unsupported { field, .. } => field
+        }
+    }
+
+    /// Moves the error by `by` bytes, for errors of a buffer that was sliced
+    /// out of a larger one.
+    pub fn shift(mut self, by: usize) -> Self {
+        match &mut self {
+            Self::Truncated { offset, .. } |
+            Self::InvalidValue { offset, .. } |
+            Self::InvalidUtf8 { offset, .. } |
+            Self::Unsupported { offset, .. } => *offset += by
+        }
+        self
+    }
+}
+
+/// Why a packet couldn't be encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The value only names a type, there's nothing to encode for it.
+    Unsupported { field: &'static str },
+    /// The value doesn't have the type its container declares for it.
+    WrongType { field: &'static str }
+}
@@ -1,29 +1,11 @@
 use std::fmt::Debug;
-use std::sync::{Mutex, MutexGuard};
-use std::collections::BTreeMap;
 
+use crate::error::DecodeError;
+use crate::reader::Reader;
 use crate::typ::CommandType;
 
-static mut FRAGMENTED_MAP: Mutex<BTreeMap<u32, FragmentedPackage>> 
-    = Mutex::new(BTreeMap::new());
-
-pub fn get_fragment_map<'a>() -> MutexGuard<'a, BTreeMap<u32, FragmentedPackage>> {
-    unsafe { FRAGMENTED_MAP.lock().unwrap() }
-}
-
-#[derive(Debug, Clone)] 
-pub struct FragmentedPackage {
-    pub total_fragments: u32,
-    pub received_fragments: u32,
-    pub bytes: Vec<u8>
-}
-
-impl FragmentedPackage {
-    fn new(total_fragments: u32, total_len: usize) -> Self {
-        Self { total_fragments, received_fragments: 0,
-            bytes: vec![0; total_len] }
-    }
-}
+pub const FLAG_RELIABLE: u8 = 1;
+pub const FLAG_UNSEQUENCED: u8 = 2;
 
 #[derive(Default, Clone, Copy)]
 pub struct CommandHeader {
@@ -119,49 +101,51 @@ impl CommandHeader {
         ret
     }
 
-    pub fn deserialize(buf: &[u8], mut offset: usize) -> Self {
+    /// Decodes the header of the command starting at `offset`. The size is
+    /// checked against the header length, not against the buffer.
+    pub fn deserialize(buf: &[u8], offset: usize)
+            -> Result<Self, DecodeError> {
+        let mut r = Reader::at(buf, offset);
+        let cmd_type = CommandType::try_from(r.u8("command type")?)
+            .map_err(|e| e.shift(offset))?;
         let mut ret = Self {
-            cmd_type: CommandType::from(buf[offset]),
-            channel_id: buf[offset + 1],
-            flags: buf[offset + 2],
-            reserved: buf[offset + 3],
-            size: u32::from_be_bytes(buf[offset+0x4..offset+0x8].try_into()
-                .unwrap()),
-            reliable_seq_num: u32::from_be_bytes(buf[offset+0x8..offset+0xc]
-                .try_into().unwrap()),
+            cmd_type,
+            channel_id: r.u8("channel id")?,
+            flags: r.u8("flags")?,
+            reserved: r.u8("reserved")?,
+            size: r.u32("size")?,
+            reliable_seq_num: r.u32("reliable sequence number")?,
             ..Default::default()
         };
 
-        offset += 0xc;
-
         match ret.cmd_type {
             CommandType::Unreliable => {
-                ret.unreliable_seq_num = Some(u32::from_be_bytes(
-                    buf[offset..offset+4].try_into().unwrap()));
+                ret.unreliable_seq_num =
+                    Some(r.u32("unreliable sequence number")?);
             }
             CommandType::Fragmented => {
-                ret.start_seq_num = Some(u32::from_be_bytes(
-                    buf[offset..offset+0x4].try_into().unwrap()));
-                ret.fragment_count = Some(u32::from_be_bytes(
-                    buf[offset+0x4..offset+0x8].try_into().unwrap()));
-                ret.fragment_num = Some(u32::from_be_bytes(
-                    buf[offset+0x8..offset+0xc].try_into().unwrap()));
-                ret.total_len = Some(u32::from_be_bytes(
-                    buf[offset+0xc..offset+0x10].try_into().unwrap()));
-                ret.fragment_offset = Some(u32::from_be_bytes(
-                    buf[offset+0x10..offset+0x14].try_into().unwrap()));
-
-                let mut map = get_fragment_map();
-                map.entry(ret.start_seq_num.unwrap())
-                    .or_insert(FragmentedPackage::new(
-                        ret.fragment_count.unwrap(),
-                        ret.total_len.unwrap() as usize
-                    ));
+                ret.start_seq_num = Some(r.u32("start sequence number")?);
+                ret.fragment_count = Some(r.u32("fragment count")?);
+                ret.fragment_num = Some(r.u32("fragment number")?);
+                ret.total_len = Some(r.u32("total length")?);
+                ret.fragment_offset = Some(r.u32("fragment offset")?);
             },
             _ => {}
         }
 
-        ret
+        if (ret.size as usize) < ret.len() {
+            return Err(DecodeError::InvalidValue { offset: offset + 4,
+                field: "size", value: ret.size });
+        }
+        Ok(ret)
+    }
+
+    pub fn is_reliable(&self) -> bool {
+        self.flags & FLAG_RELIABLE != 0
+    }
+
+    pub fn is_unsequenced(&self) -> bool {
+        self.flags & FLAG_UNSEQUENCED != 0
     }
 
     pub fn len(&self) -> usize {
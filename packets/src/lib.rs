@@ -1,9 +1,13 @@
 //! packets contains all the fun and cool packet types that are used to 
 //! communicate with the client
-//! 
-//! TODO: implement CRC and encryption
 
+pub mod command;
+pub mod crc;
+pub mod datagram;
+pub mod error;
+mod reader;
 pub mod typ;
 pub mod header;
 pub mod payload;
-pub mod photon;
\ No newline at end of file
+pub mod photon;
+pub mod tcp;
\ No newline at end of file
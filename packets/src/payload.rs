@@ -1,14 +1,70 @@
+use std::fmt::Debug;
+
+use crate::error::{DecodeError, EncodeError};
 use crate::typ::CommandType;
 use crate::photon::PhotonCommand;
-use crate::header::{get_fragment_map, CommandHeader};
+use crate::header::CommandHeader;
+use crate::reader::Reader;
 
-#[derive(Debug, Clone, Copy)]
-pub struct Connect {
+/// What a Connect proposes and its VerifyConnect settles on. Both commands
+/// carry them after their first two bytes, which only VerifyConnect uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ConnectParameters {
     pub mtu: u16,
+    pub window_size: u32,
     pub channel_count: u8,
+    pub incoming_bandwidth: u32,
+    pub outgoing_bandwidth: u32,
+    pub throttle_interval: u32,
+    pub throttle_acceleration: u32,
+    pub throttle_deceleration: u32
+}
+
+impl ConnectParameters {
+    fn deserialize(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::at(buf, 2);
+        let mtu = r.u16("mtu")?;
+        let window_size = r.u32("window size")?;
+        let channel_count = Reader::at(buf, 11).u8("channel count")?;
+        let mut r = Reader::at(buf, 12);
+        Ok(Self {
+            mtu,
+            window_size,
+            channel_count,
+            incoming_bandwidth: r.u32("incoming bandwidth")?,
+            outgoing_bandwidth: r.u32("outgoing bandwidth")?,
+            throttle_interval: r.u32("throttle interval")?,
+            throttle_acceleration: r.u32("throttle acceleration")?,
+            throttle_deceleration: r.u32("throttle deceleration")?
+        })
+    }
+
+    fn serialize(&self, buf: &mut [u8]) {
+        buf[2..4].copy_from_slice(&self.mtu.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.window_size.to_be_bytes());
+        buf[11] = self.channel_count;
+        buf[12..16].copy_from_slice(&self.incoming_bandwidth.to_be_bytes());
+        buf[16..20].copy_from_slice(&self.outgoing_bandwidth.to_be_bytes());
+        buf[20..24].copy_from_slice(&self.throttle_interval.to_be_bytes());
+        buf[24..28].copy_from_slice(
+            &self.throttle_acceleration.to_be_bytes());
+        buf[28..32].copy_from_slice(
+            &self.throttle_deceleration.to_be_bytes());
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Connect {
+    pub params: ConnectParameters,
     len: u32
 }
 
+impl Connect {
+    pub fn new(params: ConnectParameters) -> Self {
+        Self { params, len: 32 }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Ack {
     pub acked_seq_num: u32,
@@ -16,45 +72,165 @@ pub struct Ack {
     len: u32
 }
 
+impl Ack {
+    pub fn new(acked_seq_num: u32, send_time: u32) -> Self {
+        Self { acked_seq_num, send_time, len: 8 }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct VerifyConnect {
     pub peer_id: u16,
+    pub params: ConnectParameters,
     len: u32
 }
 
-#[derive(Debug, Clone, Copy)]
+impl VerifyConnect {
+    pub fn new(peer_id: u16, params: ConnectParameters) -> Self {
+        Self { peer_id, params, len: 32 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
 pub struct Ping {
     len: u32
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Ping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Why the server disconnects a peer. It's sent in the reserved byte of the
+/// Disconnect command's header, the body stays empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisconnectReason {
+    #[default]
+    Shutdown,
+    Kicked,
+    Timeout,
+    ServerFull,
+    Other(u8)
+}
+
+impl From<u8> for DisconnectReason {
+    fn from(val: u8) -> Self {
+        match val {
+            0 => Self::Shutdown,
+            1 => Self::Kicked,
+            2 => Self::Timeout,
+            3 => Self::ServerFull,
+            _ => Self::Other(val)
+        }
+    }
+}
+
+impl From<DisconnectReason> for u8 {
+    fn from(val: DisconnectReason) -> Self {
+        match val {
+            DisconnectReason::Shutdown   => 0,
+            DisconnectReason::Kicked     => 1,
+            DisconnectReason::Timeout    => 2,
+            DisconnectReason::ServerFull => 3,
+            DisconnectReason::Other(x)   => x
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
 pub struct Disconnect {
+    pub reason: DisconnectReason,
     len: u32
 }
 
+impl Disconnect {
+    pub fn new(reason: DisconnectReason) -> Self {
+        Self { reason, len: 0 }
+    }
+}
+
+/// A message sent reliably. It keeps the bytes it was sent or received as,
+/// so it's encoded once and goes back on the wire exactly as it came in.
 #[derive(Debug, Clone)]
 pub struct Reliable {
-    pub payload: PhotonCommand,
+    payload: PhotonCommand,
+    bytes: Vec<u8>,
     len: u32
 }
 
+impl Reliable {
+    pub fn new(payload: PhotonCommand) -> Result<Self, EncodeError> {
+        let bytes = payload.serialize()?;
+        Ok(Self { payload, len: bytes.len() as u32, bytes })
+    }
+
+    pub fn payload(&self) -> &PhotonCommand {
+        &self.payload
+    }
+
+    pub fn into_payload(self) -> PhotonCommand {
+        self.payload
+    }
+}
+
+/// A message sent unreliably, it keeps its bytes just like `Reliable`.
 #[derive(Debug, Clone)]
 pub struct Unreliable {
-    pub payload: PhotonCommand,
+    payload: PhotonCommand,
+    bytes: Vec<u8>,
     len: u32
 }
 
-#[derive(Debug, Clone)]
+impl Unreliable {
+    pub fn new(payload: PhotonCommand) -> Result<Self, EncodeError> {
+        let bytes = payload.serialize()?;
+        Ok(Self { payload, len: bytes.len() as u32, bytes })
+    }
+
+    pub fn payload(&self) -> &PhotonCommand {
+        &self.payload
+    }
+
+    pub fn into_payload(self) -> PhotonCommand {
+        self.payload
+    }
+}
+
+/// A single fragment of a message that was too large for one command. The
+/// fragments are reassembled by whoever tracks the peer that sent them.
+#[derive(Clone)]
 pub struct Fragmented {
-    pub payload: PhotonCommand,
+    pub bytes: Vec<u8>,
     len: u32
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Fragmented {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        let len = bytes.len() as u32;
+        Self { bytes, len }
+    }
+}
+
+impl Debug for Fragmented {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Fragmented")
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
 pub struct ServerTime {
     len: u32
 }
 
+impl ServerTime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum CommandPayload {
     None,
@@ -70,70 +246,84 @@ pub enum CommandPayload {
 }
 
 impl CommandPayload {
-    pub fn deserialize(buf: &[u8], header: CommandHeader) -> Option<Self> {
-        match header.cmd_type {
+    /// Decodes the body of a command, `buf` must hold exactly the body.
+    pub fn deserialize(buf: &[u8], header: CommandHeader)
+            -> Result<Self, DecodeError> {
+        Ok(match header.cmd_type {
             CommandType::None => {
-                Some(Self::None)
+                Self::None
             },
             CommandType::Connect => {
-                let mtu = u16::from_be_bytes(buf[2..4].try_into().unwrap());
-                let channel_count = buf[11];
-                Some(Self::Connect(Connect { mtu, channel_count, len: 32 }))
+                let params = ConnectParameters::deserialize(buf)?;
+                Self::Connect(Connect::new(params))
             },
             CommandType::Ack => {
-                let acked_seq_num = u32::from_be_bytes(buf[0..4].try_into()
-                    .unwrap());
-                let send_time = u32::from_be_bytes(buf[4..8].try_into()
-                    .unwrap());
-                Some(Self::Ack(Ack { acked_seq_num, send_time, len: 8}))
+                let mut r = Reader::new(buf);
+                let acked_seq_num = r.u32("acked sequence number")?;
+                let send_time = r.u32("send time")?;
+                Self::Ack(Ack::new(acked_seq_num, send_time))
             },
             CommandType::VerifyConnect => {
-                let peer_id = u16::from_be_bytes(buf[0..2].try_into()
-                    .unwrap());
-                Some(Self::VerifyConnect(VerifyConnect { peer_id, len: 32 }))
+                let peer_id = Reader::new(buf).u16("peer id")?;
+                let params = ConnectParameters::deserialize(buf)?;
+                Self::VerifyConnect(VerifyConnect::new(peer_id, params))
             },
             CommandType::Disconnect => {
-                Some(Self::Ping(Ping { len: 12 }))
+                Self::Disconnect(Disconnect::new(
+                    DisconnectReason::from(header.reserved)))
             },
             CommandType::Ping => {
-                Some(Self::Ping(Ping { len: 12 }))
+                Self::Ping(Ping::new())
             },
             CommandType::Reliable => {
                 let size = buf.len() as u32;
-                let payload = PhotonCommand::from(buf);
-                Some(Self::Reliable(Reliable { payload, len: 12 + size }))
+                let payload = PhotonCommand::try_from(buf)?;
+                Self::Reliable(Reliable { payload, bytes: Vec::from(buf),
+                    len: size })
             },
             CommandType::Unreliable => {
                 let size = buf.len() as u32;
-                let payload = PhotonCommand::from(buf);
-                Some(Self::Unreliable(Unreliable { payload, len: 16 + size }))
+                let payload = PhotonCommand::try_from(buf)?;
+                Self::Unreliable(Unreliable { payload, bytes: Vec::from(buf),
+                    len: size })
             },
             CommandType::Fragmented => {
-                let mut map = get_fragment_map();
-                let mut fragment = map.get_mut(&header.start_seq_num.unwrap())
-                    .expect("Sequence number not found :/");
-
-                let size = buf.len();
-                let start = header.fragment_offset.unwrap() as usize;
-                fragment.bytes[start..start+size].copy_from_slice(&buf);
-
-                fragment.received_fragments += 1;
-                if fragment.received_fragments != fragment.total_fragments {
-                    return None;
-                }
-
-                let size = fragment.bytes.len() as u32;
-                let payload = PhotonCommand::from(&*fragment.bytes);
-                map.remove(&header.start_seq_num.unwrap());
-                Some(Self::Fragmented(Fragmented { payload, len: 32 + size }))
+                Self::Fragmented(Fragmented::new(Vec::from(buf)))
             }
             CommandType::ServerTime => {
-                Some(Self::ServerTime(ServerTime { len: 12 }))
+                Self::ServerTime(ServerTime::new())
             }
-        }
+        })
     }
 
-    pub fn serialize(&self) -> Vec<u8> { Vec::new() }
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            Self::None => Vec::new(),
+            Self::Connect(p) => {
+                let mut ret = vec![0; p.len as usize];
+                p.params.serialize(&mut ret);
+                ret
+            },
+            Self::Ack(p) => {
+                let mut ret = vec![0; p.len as usize];
+                ret[0..4].copy_from_slice(&p.acked_seq_num.to_be_bytes());
+                ret[4..8].copy_from_slice(&p.send_time.to_be_bytes());
+                ret
+            },
+            Self::VerifyConnect(p) => {
+                let mut ret = vec![0; p.len as usize];
+                ret[0..2].copy_from_slice(&p.peer_id.to_be_bytes());
+                p.params.serialize(&mut ret);
+                ret
+            },
+            Self::Ping(_) | Self::Disconnect(_) | Self::ServerTime(_) => {
+                Vec::new()
+            },
+            Self::Reliable(p) => p.bytes.clone(),
+            Self::Unreliable(p) => p.bytes.clone(),
+            Self::Fragmented(p) => p.bytes.clone()
+        }
+    }
 
     pub fn len(&self) -> u32 {
         match self {
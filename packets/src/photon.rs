@@ -1,6 +1,9 @@
 use std::collections::BTreeMap;
 use std::mem;
 
+use crate::error::{DecodeError, EncodeError};
+use crate::reader::Reader;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PhotonCode {
     ClientKey,
@@ -73,7 +76,7 @@ impl Into<u8> for MessageType {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum GpType {
     Array,
     Boolean,
@@ -141,7 +144,7 @@ impl Into<u8> for GpType {
             Self::Dictionary => 68,
             Self::Double => 100,
             Self::Hashtable => 104,
-            Self::Integer => 109,
+            Self::Integer => 105,
             Self::IntegerArray => 110,
             Self::Long => 108,
             Self::String => 115,
@@ -191,9 +194,26 @@ impl Into<f64> for Double {
     }
 }
 
+/// How deep hashtables and dictionaries may nest. Each level costs a few
+/// bytes on the wire but a stack frame when decoding, so without a limit a
+/// single message could overflow the stack.
+const MAX_DEPTH: u32 = 32;
+
+/// Operation and event parameters in the order they were sent in.
+pub type Parameters = Vec<(u8, Option<Value>)>;
+
+/// A typed dictionary. Unlike hashtables its keys and values may all share a
+/// type that's only sent once, `None` means each one carries its own.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Dictionary {
+    pub key_type: Option<GpType>,
+    pub value_type: Option<GpType>,
+    pub entries: BTreeMap<Option<Value>, Option<Value>>
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Value {
-    Dictionary,
+    Dictionary(Dictionary),
     StringArray(Vec<String>),
     Byte(u8),
     Custom,
@@ -215,103 +235,280 @@ pub enum Value {
 }
 
 impl Value {
-    fn parse(t: GpType, buf: &[u8], cur: &mut usize) -> Option<Self> {
-        match t {
-            GpType::Array => todo!("Array not yet implemented"),
-            GpType::Boolean => Self::parse_bool(buf, cur),
-            GpType::Byte => Self::parse_byte(buf, cur),
-            GpType::ByteArray => Self::parse_byte_array(buf, cur),
-            GpType::ObjectArray => todo!("ObjectArray not yet implemented"),
-            GpType::Short => todo!("Short not yet implemented"),
-            GpType::Float => todo!("Float not yet implemented"),
-            GpType::Dictionary => todo!("Dictionary not yet implemented"),
-            GpType::Double => todo!("Double not yet implemented"),
-            GpType::Hashtable => Self::parse_hashtable(buf, cur),
-            GpType::Integer => Self::parse_int(buf, cur),
-            GpType::IntegerArray => todo!("IntegerArray not yet implemented"),
-            GpType::Long => todo!("Long not yet implemented"),
-            GpType::String => Self::parse_string(buf, cur),
-            GpType::StringArray => todo!("StringArray not yet implemented"),
-            GpType::Custom => todo!("Custom not yet implemented"),
-            GpType::Null => None,
-            GpType::EventData => todo!("EventData not yet implemented"),
-            GpType::OperationRequest => todo!("OperationRequest not yet implemented"),
-            GpType::OperationResponse => todo!("OperationResponse not yet implemented"),
-            GpType::Unknown => None
+    fn parse_type(r: &mut Reader, field: &'static str)
+            -> Result<GpType, DecodeError> {
+        let offset = r.pos();
+        let val = r.u8(field)?;
+        match GpType::from(val) {
+            GpType::Unknown => Err(DecodeError::InvalidValue { offset, field,
+                value: val as u32 }),
+            t => Ok(t)
         }
     }
 
-    fn parse_bool(buf: &[u8], cur: &mut usize) -> Option<Self> {
-        let ret = Self::Boolean(buf[*cur] != 0);
-        *cur += 1;
-        Some(ret)
+    /// Type of a dictionary's keys or values, `None` if each entry is typed
+    /// on its own.
+    fn parse_entry_type(r: &mut Reader, field: &'static str)
+            -> Result<Option<GpType>, DecodeError> {
+        let offset = r.pos();
+        let val = r.u8(field)?;
+        match GpType::from(val) {
+            GpType::Null => Ok(None),
+            GpType::Unknown if val == 0 => Ok(None),
+            GpType::Unknown => Err(DecodeError::InvalidValue { offset, field,
+                value: val as u32 }),
+            t => Ok(Some(t))
+        }
     }
 
-    fn parse_byte(buf: &[u8], cur: &mut usize) -> Option<Self> {
-        let ret = Self::Byte(buf[*cur]);
-        *cur += 1;
-        Some(ret)
+    /// Decodes a value of type `t` that's nested `depth` hashtables or
+    /// dictionaries deep.
+    fn parse(t: GpType, r: &mut Reader, depth: u32)
+            -> Result<Option<Self>, DecodeError> {
+        let ret = match t {
+            GpType::Boolean => Self::parse_bool(r)?,
+            GpType::Byte => Self::parse_byte(r)?,
+            GpType::ByteArray => Self::parse_byte_array(r)?,
+            GpType::Hashtable => Self::parse_hashtable(r, depth + 1)?,
+            GpType::Dictionary => Self::parse_dictionary(r, depth + 1)?,
+            GpType::Integer => Self::parse_int(r)?,
+            GpType::String => Self::parse_string(r)?,
+            GpType::Short => Self::Short(r.i16("short")?),
+            GpType::Long => Self::Long(r.i64("long")?),
+            GpType::Float => Self::Float(Float { val: r.u32("float")? }),
+            GpType::Double => Self::Double(Double { val: r.u64("double")? }),
+            GpType::IntegerArray => Self::parse_int_array(r)?,
+            GpType::StringArray => Self::parse_string_array(r)?,
+            GpType::Null => return Ok(None),
+            GpType::Array | GpType::ObjectArray | GpType::Custom | GpType::EventData | GpType::OperationRequest |
+            GpType::OperationResponse | GpType::Unknown => {
+                return Err(DecodeError::Unsupported { offset: r.pos(),
+                    field: "value" });
+            }
+        };
+        Ok(Some(ret))
+    }
+
+    fn parse_bool(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self::Boolean(r.u8("boolean")? != 0))
+    }
+
+    fn parse_byte(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self::Byte(r.u8("byte")?))
     }
 
-    fn parse_byte_array(buf: &[u8], cur: &mut usize) -> Option<Self> {
-        let num = u32::from_be_bytes(buf[*cur..*cur+4].try_into()
-            .unwrap()) as usize;
-        *cur += 4;
-        let ret = Value::ByteArray(Vec::from(&buf[*cur..*cur+num]));
-        *cur += num;
-        Some(ret)
+    fn parse_byte_array(r: &mut Reader) -> Result<Self, DecodeError> {
+        let num = r.u32("byte array length")? as usize;
+        Ok(Value::ByteArray(Vec::from(r.bytes(num, "byte array")?)))
     }
 
-    fn parse_hashtable(buf: &[u8], cur: &mut usize) 
-            -> Option<Self> {
-        let num = u16::from_be_bytes(buf[*cur..*cur+2].try_into().unwrap());
+    fn parse_hashtable(r: &mut Reader, depth: u32)
+            -> Result<Self, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(DecodeError::InvalidValue { offset: r.pos(),
+                field: "hashtable depth", value: depth });
+        }
+        let num = r.u16("hashtable length")?;
         let mut ret = BTreeMap::new();
-        *cur += 2;
         for _ in 0..num {
-            let t1 = GpType::from(buf[*cur]);
-            *cur += 1;
-            let key = Self::parse(t1, buf, cur);
-            let t2 = GpType::from(buf[*cur]);
-            *cur += 1;
-            let value = Self::parse(t2, buf, cur);
+            let t1 = Self::parse_type(r, "hashtable key type")?;
+            let key = Self::parse(t1, r, depth)?;
+            let t2 = Self::parse_type(r, "hashtable value type")?;
+            let value = Self::parse(t2, r, depth)?;
             ret.insert(key, value);
         }
-        Some(Self::HashTable(ret))
+        Ok(Self::HashTable(ret))
     }
 
-    fn parse_int(buf: &[u8], cur: &mut usize) -> Option<Self> {
-        let ret = Self::Integer(
-            i32::from_be_bytes(buf[*cur..*cur+4].try_into().unwrap()));
-        *cur += 4;
-        Some(ret)
+    fn parse_dictionary(r: &mut Reader, depth: u32)
+            -> Result<Self, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(DecodeError::InvalidValue { offset: r.pos(),
+                field: "dictionary depth", value: depth });
+        }
+        let key_type = Self::parse_entry_type(r, "dictionary key type")?;
+        let value_type = Self::parse_entry_type(r, "dictionary value type")?;
+        let num = r.u16("dictionary length")?;
+        let mut entries = BTreeMap::new();
+        for _ in 0..num {
+            let t1 = match key_type {
+                Some(t) => t,
+                None => Self::parse_type(r, "dictionary key type")?
+            };
+            let key = Self::parse(t1, r, depth)?;
+            let t2 = match value_type {
+                Some(t) => t,
+                None => Self::parse_type(r, "dictionary value type")?
+            };
+            let value = Self::parse(t2, r, depth)?;
+            entries.insert(key, value);
+        }
+        Ok(Self::Dictionary(Dictionary { key_type, value_type, entries }))
+    }
+
+    fn parse_int(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self::Integer(r.i32("integer")?))
+    }
+
+    fn parse_int_array(r: &mut Reader) -> Result<Self, DecodeError> {
+        let num = r.u32("integer array length")?;
+        let mut ret = Vec::new();
+        for _ in 0..num {
+            ret.push(r.i32("integer")?);
+        }
+        Ok(Self::IntArray(ret))
     }
 
-    fn parse_string(buf: &[u8], cur: &mut usize) -> Option<Self> {
-        let num = u16::from_be_bytes(buf[*cur..*cur+2].try_into()
-            .unwrap()) as usize;
-        *cur += 2;
-        if num == 0 { return Some(Self::String(String::from(""))); }
-        let mut string = String::from_utf8(
-            Vec::from(&buf[*cur..*cur+num])).unwrap();
+    fn parse_raw_string(r: &mut Reader) -> Result<String, DecodeError> {
+        let num = r.u16("string length")? as usize;
+        let offset = r.pos();
+        let bytes = r.bytes(num, "string")?;
+        let mut string = String::from_utf8(Vec::from(bytes))
+            .map_err(|_| DecodeError::InvalidUtf8 { offset,
+                field: "string" })?;
         string.retain(|c| c != '\0');
-        *cur += num;
-        Some(Self::String(string))
+        Ok(string)
     }
 
-    fn parse_parameter_table(buf: &[u8], cur: &mut usize) 
-            -> BTreeMap<u8, Option<Self>> {
-        let num = u16::from_be_bytes(buf[*cur..*cur+2].try_into()
-            .unwrap());
-        *cur += 2;
-        let mut ret = BTreeMap::new();
+    fn parse_string(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self::String(Self::parse_raw_string(r)?))
+    }
+
+    fn parse_string_array(r: &mut Reader) -> Result<Self, DecodeError> {
+        let num = r.u16("string array length")?;
+        let mut ret = Vec::new();
         for _ in 0..num {
-            let key = buf[*cur];
-            let t = GpType::from(buf[*cur+1]);
-            *cur += 2;
-            let val = Self::parse(t, buf, cur);
-            ret.insert(key, val);
+            ret.push(Self::parse_raw_string(r)?);
         }
-        ret
+        Ok(Self::StringArray(ret))
+    }
+
+    fn gp_type(&self) -> GpType {
+        match self {
+            Self::Dictionary(_) => GpType::Dictionary,
+            Self::StringArray(_) => GpType::StringArray,
+            Self::Byte(_) => GpType::Byte,
+            Self::Custom => GpType::Custom,
+            Self::Double(_) => GpType::Double,
+            Self::EventData => GpType::EventData,
+            Self::Float(_) => GpType::Float,
+            Self::HashTable(_) => GpType::Hashtable,
+            Self::Integer(_) => GpType::Integer,
+            Self::Short(_) => GpType::Short,
+            Self::Long(_) => GpType::Long,
+            Self::IntArray(_) => GpType::IntegerArray,
+            Self::Boolean(_) => GpType::Boolean,
+            Self::OperationResponse => GpType::OperationResponse,
+            Self::OperationRequest => GpType::OperationRequest,
+            Self::String(_) => GpType::String,
+            Self::ByteArray(_) => GpType::ByteArray,
+            Self::Array => GpType::Array,
+            Self::ObjectArray => GpType::ObjectArray,
+        }
+    }
+
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match self {
+            Self::Boolean(v) => buf.push(*v as u8),
+            Self::Byte(v) => buf.push(*v),
+            Self::ByteArray(v) => {
+                buf.extend_from_slice(&(v.len() as u32).to_be_bytes());
+                buf.extend_from_slice(v);
+            },
+            Self::HashTable(v) => {
+                buf.extend_from_slice(&(v.len() as u16).to_be_bytes());
+                for (key, value) in v {
+                    Self::serialize_typed(key, buf)?;
+                    Self::serialize_typed(value, buf)?;
+                }
+            },
+            Self::Dictionary(v) => {
+                buf.push(v.key_type.map_or(0, Into::into));
+                buf.push(v.value_type.map_or(0, Into::into));
+                buf.extend_from_slice(&(v.entries.len() as u16).to_be_bytes());
+                for (key, value) in &v.entries {
+                    Self::serialize_entry(v.key_type, key, buf)?;
+                    Self::serialize_entry(v.value_type, value, buf)?;
+                }
+            },
+            Self::Integer(v) => buf.extend_from_slice(&v.to_be_bytes()),
+            Self::Short(v) => buf.extend_from_slice(&v.to_be_bytes()),
+            Self::Long(v) => buf.extend_from_slice(&v.to_be_bytes()),
+            Self::Float(v) => buf.extend_from_slice(&v.val.to_be_bytes()),
+            Self::Double(v) => buf.extend_from_slice(&v.val.to_be_bytes()),
+            Self::IntArray(v) => {
+                buf.extend_from_slice(&(v.len() as u32).to_be_bytes());
+                for x in v {
+                    buf.extend_from_slice(&x.to_be_bytes());
+                }
+            },
+            Self::String(v) => Self::serialize_raw_string(v, buf),
+            Self::StringArray(v) => {
+                buf.extend_from_slice(&(v.len() as u16).to_be_bytes());
+                for x in v {
+                    Self::serialize_raw_string(x, buf);
+                }
+            },
+            Self::Custom | Self::EventData |
+            Self::OperationResponse | Self::OperationRequest | Self::Array |
+            Self::ObjectArray => {
+                return Err(EncodeError::Unsupported { field: "value" });
+            }
+        }
+        Ok(())
+    }
+
+    fn serialize_raw_string(val: &str, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(val.len() as u16).to_be_bytes());
+        buf.extend_from_slice(val.as_bytes());
+    }
+
+    fn serialize_typed(val: &Option<Self>, buf: &mut Vec<u8>)
+            -> Result<(), EncodeError> {
+        match val {
+            Some(v) => {
+                buf.push(v.gp_type().into());
+                v.serialize(buf)
+            },
+            None => {
+                buf.push(GpType::Null.into());
+                Ok(())
+            }
+        }
+    }
+
+    /// Encodes a dictionary's key or value, which only carries its type if
+    /// the dictionary doesn't declare one for all of them.
+    fn serialize_entry(t: Option<GpType>, val: &Option<Self>,
+            buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match (t, val) {
+            (None, _) => Self::serialize_typed(val, buf),
+            (Some(t), Some(v)) if v.gp_type() == t => v.serialize(buf),
+            _ => Err(EncodeError::WrongType { field: "dictionary entry" })
+        }
+    }
+
+    fn serialize_parameter_table(table: &Parameters,
+            buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+        buf.extend_from_slice(&(table.len() as u16).to_be_bytes());
+        for (key, val) in table {
+            buf.push(*key);
+            Self::serialize_typed(val, buf)?;
+        }
+        Ok(())
+    }
+
+    fn parse_parameter_table(r: &mut Reader)
+            -> Result<Parameters, DecodeError> {
+        let num = r.u16("parameter count")?;
+        let mut ret = Vec::new();
+        for _ in 0..num {
+            let key = r.u8("parameter key")?;
+            let t = Self::parse_type(r, "parameter type")?;
+            let val = Self::parse(t, r, 0)?;
+            ret.push((key, val));
+        }
+        Ok(ret)
     }
 }
 
@@ -342,26 +539,73 @@ pub struct InitResponse {
 pub struct Operation {
     _msg_type: MessageType,
     _opcode: u8,
-    _values: BTreeMap<u8, Option<Value>>
+    _values: Parameters
 }
 
 #[derive(Debug, Clone)]
 pub struct Event {
     _msg_type: MessageType,
     _opcode: u8,
-    _values: BTreeMap<u8, Option<Value>>
+    _values: Parameters
 }
 
 #[derive(Debug, Clone)]
 pub struct InternalOperationRequest {
     _msg_type: MessageType,
     _opcode: u8,
-    _values: BTreeMap<u8, Option<Value>>
+    _values: Parameters
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Operation {
+    pub fn opcode(&self) -> u8 {
+        self._opcode
+    }
+
+    pub fn parameter(&self, key: u8) -> Option<&Value> {
+        self._values.iter().find(|x| x.0 == key)?.1.as_ref()
+    }
+}
+
+impl InternalOperationRequest {
+    pub fn opcode(&self) -> u8 {
+        self._opcode
+    }
+
+    pub fn parameter(&self, key: u8) -> Option<&Value> {
+        self._values.iter().find(|x| x.0 == key)?.1.as_ref()
+    }
+}
+
+/// A message whose body is encrypted with the key from InitEncryption. The
+/// high bit of the message type marks it as such.
+#[derive(Clone)]
 pub struct Encrypted {
-    _packet_len: usize
+    msg_type: u8,
+    bytes: Vec<u8>
+}
+
+impl Encrypted {
+    const FLAG: u8 = 0x80;
+
+    pub fn new(msg_type: MessageType, bytes: Vec<u8>) -> Self {
+        Self { msg_type: Into::<u8>::into(msg_type) | Self::FLAG, bytes }
+    }
+
+    /// The type of the message once decrypted.
+    pub fn msg_type(&self) -> MessageType {
+        MessageType::from(self.msg_type & !Self::FLAG)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl std::fmt::Debug for Encrypted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Encrypted[ msg_type: 0x{:x}, packet_len: {} ]",
+            self.msg_type, self.bytes.len() + 2)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -369,14 +613,37 @@ pub struct OperationResponse {
     _opcode: u8,
     _retcode: u16,
     _dbg_msg: Option<Value>,
-    _parameters: BTreeMap<u8, Option<Value>>
+    _parameters: Parameters
+}
+
+impl OperationResponse {
+    pub fn new(opcode: u8, retcode: u16, parameters: Parameters) -> Self {
+        Self {
+            _opcode: opcode,
+            _retcode: retcode,
+            _dbg_msg: None,
+            _parameters: parameters
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct InternalOperationResponse {
-    _msg_type: MessageType,
     _opcode: u8,
-    _values: BTreeMap<u8, Option<Value>>
+    _retcode: u16,
+    _dbg_msg: Option<Value>,
+    _parameters: Parameters
+}
+
+impl InternalOperationResponse {
+    pub fn new(opcode: u8, retcode: u16, parameters: Parameters) -> Self {
+        Self {
+            _opcode: opcode,
+            _retcode: retcode,
+            _dbg_msg: None,
+            _parameters: parameters
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -391,84 +658,249 @@ pub enum PhotonCommand {
     InternalOperationResponse(InternalOperationResponse)
 }
 
-impl From<&[u8]> for PhotonCommand {
-    fn from(buf: &[u8]) -> Self {
-        let mut cur = 0;
-        assert!(buf[cur] == 0xf3 || buf[cur] == 0xfd);
-        cur += 1;
-        let msg_type = MessageType::from(buf[cur]);
+impl TryFrom<&[u8]> for PhotonCommand {
+    type Error = DecodeError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        let mut r = Reader::new(buf);
+        let magic = r.u8("magic")?;
+        if magic != 0xf3 && magic != 0xfd {
+            return Err(DecodeError::InvalidValue { offset: 0, field: "magic",
+                value: magic as u32 });
+        }
+        let raw_type = r.u8("message type")?;
+        let msg_type = MessageType::from(raw_type);
         if let MessageType::Unknown(v) = msg_type {
-            if buf[cur] & 127 != 1 && buf[cur] & 128 > 1 {
-                return Self::Encrypted(Encrypted { _packet_len: buf.len() });
-            } else {
-                panic!("No such message type and not encrypted: {}", v);
+            if v & 127 != 1 && v & 128 > 1 {
+                return Ok(Self::Encrypted(Encrypted { msg_type: v,
+                    bytes: Vec::from(r.rest()) }));
             }
+            return Err(DecodeError::InvalidValue { offset: 1,
+                field: "message type", value: v as u32 });
         }
-        cur += 1;
 
         match msg_type {
             MessageType::Init => {
-                let protocol_version = [buf[2], buf[3]];
-                let client_sdk_id = buf[4] >> 1;
-                let client_version = [buf[5] >> 4, buf[5] & ((1 << 4) - 1),
-                    buf[6], buf[7]];
-                let mut app_id = String::from_utf8(Vec::from(&buf[9..]))
-                    .unwrap();
+                let protocol_version = r.array("protocol version")?;
+                let client_sdk_id = r.u8("client sdk id")? >> 1;
+                let version = r.array::<3>("client version")?;
+                let client_version = [version[0] >> 4,
+                    version[0] & ((1 << 4) - 1), version[1], version[2]];
+                r.u8("reserved")?;
+                let offset = r.pos();
+                let mut app_id = String::from_utf8(Vec::from(r.rest()))
+                    .map_err(|_| DecodeError::InvalidUtf8 { offset,
+                        field: "app id" })?;
                 app_id.retain(|c| c != '\0');
-                return Self::Init(Init { protocol_version, client_sdk_id,
-                    client_version, app_id });
+                return Ok(Self::Init(Init { protocol_version, client_sdk_id,
+                    client_version, app_id }));
             },
             MessageType::InitResponse => {
-                let num = buf[2];
-                return Self::InitResponse(InitResponse { _acked_num: num });
+                let num = r.u8("acked number")?;
+                return Ok(Self::InitResponse(InitResponse {
+                    _acked_num: num }));
             },
-            MessageType::OperationResponse => {
-                let opcode = buf[cur];
-                cur += 1;
-                let retcode = u16::from_be_bytes(buf[cur..cur+2].try_into()
-                    .unwrap());
-                cur += 2;
-                let typ = GpType::from(buf[cur]);
-                cur += 1;
-                let dbg_msg = Value::parse(typ, buf, &mut cur);
-                let parameters = 
-                    Value::parse_parameter_table(buf, &mut cur);
-                return Self::OperationResponse(
-                    OperationResponse { 
+            MessageType::OperationResponse |
+            MessageType::InternalOperationResponse => {
+                let opcode = r.u8("opcode")?;
+                let retcode = r.u16("return code")?;
+                let typ = Value::parse_type(&mut r, "debug message type")?;
+                let dbg_msg = Value::parse(typ, &mut r, 0)?;
+                let parameters = Value::parse_parameter_table(&mut r)?;
+                if msg_type == MessageType::InternalOperationResponse {
+                    return Ok(Self::InternalOperationResponse(
+                        InternalOperationResponse {
+                            _opcode: opcode,
+                            _retcode: retcode,
+                            _dbg_msg: dbg_msg,
+                            _parameters: parameters
+                        }));
+                }
+                return Ok(Self::OperationResponse(
+                    OperationResponse {
                         _opcode: opcode,
                         _retcode: retcode,
                         _dbg_msg: dbg_msg,
-                        _parameters: parameters 
-                    });
+                        _parameters: parameters
+                    }));
             },
             MessageType::Operation | MessageType::InternalOperationRequest |
-            MessageType::InternalOperationResponse | MessageType::Event => {}
-            _ => todo!("Not yet implemented: {:?} {:?}", msg_type, buf)
+            MessageType::Event => {}
+            _ => return Err(DecodeError::Unsupported { offset: 1,
+                field: "message type" })
         }
 
-        let opcode = buf[cur];
-        cur += 1;
-        let values = Value::parse_parameter_table(buf, &mut cur);
+        let opcode = r.u8("opcode")?;
+        let values = Value::parse_parameter_table(&mut r)?;
 
-        match msg_type {
+        Ok(match msg_type {
             MessageType::Operation => {
-                Self::Operation(Operation { 
+                Self::Operation(Operation {
                     _msg_type: msg_type, _opcode: opcode, _values: values })
             },
             MessageType::InternalOperationRequest => {
-                Self::InternalOperationRequest(InternalOperationRequest { 
+                Self::InternalOperationRequest(InternalOperationRequest {
                     _msg_type: msg_type, _opcode: opcode, _values: values })
             },
-            MessageType::InternalOperationResponse => {
-                Self::InternalOperationResponse(InternalOperationResponse { 
+            _ => {
+                Self::Event(Event {
                     _msg_type: msg_type, _opcode: opcode, _values: values })
+            }
+        })
+    }
+}
+
+impl PhotonCommand {
+    const MAGIC: u8 = 0xf3;
+    const APP_ID_LEN: usize = 32;
+
+    /// Fails for values that only name their type, like `Value::Custom`.
+    pub fn serialize(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut ret = vec![Self::MAGIC];
+        match self {
+            Self::Init(p) => {
+                ret.push(MessageType::Init.into());
+                ret.extend_from_slice(&p.protocol_version);
+                ret.push(p.client_sdk_id << 1);
+                ret.push(p.client_version[0] << 4 | p.client_version[1]);
+                ret.extend_from_slice(&p.client_version[2..]);
+                ret.push(0);
+                ret.extend_from_slice(p.app_id.as_bytes());
+                if p.app_id.len() < Self::APP_ID_LEN {
+                    ret.resize(ret.len() + Self::APP_ID_LEN - p.app_id.len(), 0);
+                }
             },
-            MessageType::Event => {
-                Self::Event(Event { 
-                    _msg_type: msg_type, _opcode: opcode, _values: values })
+            Self::InitResponse(p) => {
+                ret.push(MessageType::InitResponse.into());
+                ret.push(p._acked_num);
+            },
+            Self::Operation(p) => {
+                ret.push(p._msg_type.into());
+                ret.push(p._opcode);
+                Value::serialize_parameter_table(&p._values, &mut ret)?;
+            },
+            Self::Event(p) => {
+                ret.push(p._msg_type.into());
+                ret.push(p._opcode);
+                Value::serialize_parameter_table(&p._values, &mut ret)?;
             },
-            _ => todo!("Not yet implemented: {:?}", msg_type)
+            Self::InternalOperationRequest(p) => {
+                ret.push(p._msg_type.into());
+                ret.push(p._opcode);
+                Value::serialize_parameter_table(&p._values, &mut ret)?;
+            },
+            Self::Encrypted(p) => {
+                ret.push(p.msg_type);
+                ret.extend_from_slice(&p.bytes);
+            },
+            Self::OperationResponse(p) => {
+                ret.push(MessageType::OperationResponse.into());
+                Self::serialize_response(p._opcode, p._retcode, &p._dbg_msg,
+                    &p._parameters, &mut ret)?;
+            },
+            Self::InternalOperationResponse(p) => {
+                ret.push(MessageType::InternalOperationResponse.into());
+                Self::serialize_response(p._opcode, p._retcode, &p._dbg_msg,
+                    &p._parameters, &mut ret)?;
+            }
         }
+        Ok(ret)
+    }
 
+    fn serialize_response(opcode: u8, retcode: u16, dbg_msg: &Option<Value>,
+            parameters: &Parameters, buf: &mut Vec<u8>)
+            -> Result<(), EncodeError> {
+        buf.push(opcode);
+        buf.extend_from_slice(&retcode.to_be_bytes());
+        Value::serialize_typed(dbg_msg, buf)?;
+        Value::serialize_parameter_table(parameters, buf)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An operation whose only parameter is a hashtable with a single entry,
+    /// keyed with another such hashtable, `depth` levels deep.
+    fn nested_operation(depth: usize) -> Vec<u8> {
+        let hashtable: u8 = GpType::Hashtable.into();
+        let null: u8 = GpType::Null.into();
+        let mut ret = vec![0xf3, MessageType::Operation.into(), 0, 0, 1, 0];
+        for _ in 0..depth {
+            ret.push(hashtable);
+            ret.extend_from_slice(&1u16.to_be_bytes());
+        }
+        ret.push(null);
+        ret.extend(std::iter::repeat(null).take(depth));
+        ret
+    }
+
+    #[test]
+    fn shallow_hashtables_decode() {
+        let buf = nested_operation(MAX_DEPTH as usize);
+        assert!(PhotonCommand::try_from(&*buf).is_ok());
+    }
+
+    #[test]
+    fn deep_hashtables_are_rejected() {
+        let buf = nested_operation(MAX_DEPTH as usize + 1);
+        assert!(matches!(PhotonCommand::try_from(&*buf),
+            Err(DecodeError::InvalidValue { field: "hashtable depth", .. })));
+
+        let buf = nested_operation(50_000);
+        assert!(PhotonCommand::try_from(&*buf).is_err());
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let parameters = vec![
+            (0, Some(Value::Short(-2))),
+            (1, Some(Value::Long(-3))),
+            (2, Some(Value::Float(1.5.into()))),
+            (3, Some(Value::Double((-0.25).into()))),
+            (4, Some(Value::IntArray(vec![1, -1, i32::MAX]))),
+            (5, Some(Value::StringArray(vec![String::new(), "gluon".into()]))),
+            (6, Some(Value::Dictionary(Dictionary {
+                key_type: Some(GpType::Byte),
+                value_type: None,
+                entries: BTreeMap::from([
+                    (Some(Value::Byte(0)), Some(Value::Integer(1))),
+                    (Some(Value::Byte(1)), None)
+                ])
+            }))),
+            (7, None)
+        ];
+        let msg = PhotonCommand::InternalOperationResponse(
+            InternalOperationResponse::new(1, 0, parameters.clone()));
+        let buf = msg.serialize().unwrap();
+        match PhotonCommand::try_from(&*buf) {
+            Ok(PhotonCommand::InternalOperationResponse(x)) => {
+                assert_eq!(x._parameters, parameters);
+            },
+            x => panic!("{:?}", x)
+        }
+    }
+
+    #[test]
+    fn dataless_values_are_unencodable() {
+        let msg = PhotonCommand::InternalOperationResponse(
+            InternalOperationResponse::new(1, 0, vec![(0, Some(Value::Custom))]));
+        assert_eq!(msg.serialize().unwrap_err(),
+            EncodeError::Unsupported { field: "value" });
+    }
+
+    #[test]
+    fn dictionary_entries_need_the_declared_type() {
+        let dictionary = Dictionary {
+            key_type: Some(GpType::Byte),
+            value_type: Some(GpType::Integer),
+            entries: BTreeMap::from([(Some(Value::Byte(0)), None)])
+        };
+        let msg = PhotonCommand::InternalOperationResponse(
+            InternalOperationResponse::new(1, 0,
+                vec![(0, Some(Value::Dictionary(dictionary)))]));
+        assert_eq!(msg.serialize().unwrap_err(),
+            EncodeError::WrongType { field: "dictionary entry" });
+    }
+}
@@ -0,0 +1,71 @@
+use crate::error::DecodeError;
+
+/// Cursor over a buffer that reads big endian fields and reports reads past
+/// its end as `DecodeError::Truncated`.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self::at(buf, 0)
+    }
+
+    pub fn at(buf: &'a [u8], pos: usize) -> Self {
+        Self { buf, pos }
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn bytes(&mut self, len: usize, field: &'static str)
+            -> Result<&'a [u8], DecodeError> {
+        let ret = self.pos.checked_add(len)
+            .and_then(|end| self.buf.get(self.pos..end))
+            .ok_or(DecodeError::Truncated { offset: self.pos, field })?;
+        self.pos += len;
+        Ok(ret)
+    }
+
+    pub fn array<const N: usize>(&mut self, field: &'static str)
+            -> Result<[u8; N], DecodeError> {
+        Ok(self.bytes(N, field)?.try_into().unwrap())
+    }
+
+    /// Everything that's left of the buffer.
+    pub fn rest(&mut self) -> &'a [u8] {
+        let ret = self.buf.get(self.pos..).unwrap_or_default();
+        self.pos += ret.len();
+        ret
+    }
+
+    pub fn u8(&mut self, field: &'static str) -> Result<u8, DecodeError> {
+        Ok(self.array::<1>(field)?[0])
+    }
+
+    pub fn u16(&mut self, field: &'static str) -> Result<u16, DecodeError> {
+        Ok(u16::from_be_bytes(self.array(field)?))
+    }
+
+    pub fn u32(&mut self, field: &'static str) -> Result<u32, DecodeError> {
+        Ok(u32::from_be_bytes(self.array(field)?))
+    }
+
+    pub fn i16(&mut self, field: &'static str) -> Result<i16, DecodeError> {
+        Ok(i16::from_be_bytes(self.array(field)?))
+    }
+
+    pub fn i32(&mut self, field: &'static str) -> Result<i32, DecodeError> {
+        Ok(i32::from_be_bytes(self.array(field)?))
+    }
+
+    pub fn i64(&mut self, field: &'static str) -> Result<i64, DecodeError> {
+        Ok(i64::from_be_bytes(self.array(field)?))
+    }
+
+    pub fn u64(&mut self, field: &'static str) -> Result<u64, DecodeError> {
+        Ok(u64::from_be_bytes(self.array(field)?))
+    }
+}
@@ -0,0 +1,178 @@
+use crate::error::{DecodeError, EncodeError};
+use crate::photon::PhotonCommand;
+use crate::reader::Reader;
+
+const FRAME_MAGIC: u8 = 0xfb;
+const PING_MAGIC: u8 = 0xf0;
+/// Magic, length, channel and the reliable flag.
+pub const FRAME_HEADER_LEN: usize = 7;
+
+/// Who sent a frame. Both send pings, but only the server's answer carries
+/// its own time as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sender {
+    Client,
+    Server
+}
+
+/// The client pings with its time, the server echoes it along with its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ping {
+    pub server_time: Option<u32>,
+    pub client_time: u32
+}
+
+impl Ping {
+    fn len(sender: Sender) -> usize {
+        match sender {
+            Sender::Client => 5,
+            Sender::Server => 9
+        }
+    }
+
+    /// Pings hold no values, so unlike messages they always encode.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = vec![PING_MAGIC];
+        if let Some(server_time) = self.server_time {
+            ret.extend_from_slice(&server_time.to_be_bytes());
+        }
+        ret.extend_from_slice(&self.client_time.to_be_bytes());
+        ret
+    }
+}
+
+/// One unit of Photon's TCP stream, a ping or a message framed with its
+/// length. Messages aren't wrapped in commands like over UDP, so there are no
+/// sequence numbers or acks.
+#[derive(Debug, Clone)]
+pub enum Frame {
+    Ping(Ping),
+    Message {
+        channel_id: u8,
+        reliable: bool,
+        msg: PhotonCommand
+    }
+}
+
+impl Frame {
+    /// How many bytes the frame at the start of `buf` takes, `None` until
+    /// enough of it arrived to tell.
+    pub fn len(buf: &[u8], sender: Sender)
+            -> Result<Option<usize>, DecodeError> {
+        let Some(&magic) = buf.first() else {
+            return Ok(None);
+        };
+        match magic {
+            PING_MAGIC => Ok(Some(Ping::len(sender))),
+            FRAME_MAGIC => {
+                let Ok(len) = Reader::at(buf, 1).u32("length") else {
+                    return Ok(None);
+                };
+                if (len as usize) < FRAME_HEADER_LEN {
+                    return Err(DecodeError::InvalidValue { offset: 1,
+                        field: "length", value: len });
+                }
+                Ok(Some(len as usize))
+            },
+            _ => Err(DecodeError::InvalidValue { offset: 0, field: "magic",
+                value: magic as u32 })
+        }
+    }
+
+    /// Decodes the frame at the start of `buf`, which has to hold all of it.
+    pub fn deserialize(buf: &[u8], sender: Sender)
+            -> Result<Self, DecodeError> {
+        let mut r = Reader::new(buf);
+        match r.u8("magic")? {
+            PING_MAGIC => {
+                let server_time = match sender {
+                    Sender::Client => None,
+                    Sender::Server => Some(r.u32("server time")?)
+                };
+                let client_time = r.u32("client time")?;
+                Ok(Self::Ping(Ping { server_time, client_time }))
+            },
+            FRAME_MAGIC => {
+                let len = r.u32("length")? as usize;
+                let channel_id = r.u8("channel id")?;
+                let reliable = r.u8("reliable")? != 0;
+                let body = r.bytes(len.saturating_sub(FRAME_HEADER_LEN),
+                    "message")?;
+                let msg = PhotonCommand::try_from(body)
+                    .map_err(|e| e.shift(FRAME_HEADER_LEN))?;
+                Ok(Self::Message { channel_id, reliable, msg })
+            },
+            magic => Err(DecodeError::InvalidValue { offset: 0,
+                field: "magic", value: magic as u32 })
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, EncodeError> {
+        Ok(match self {
+            Self::Ping(p) => p.serialize(),
+            Self::Message { channel_id, reliable, msg } => {
+                let body = msg.serialize()?;
+                let len = (FRAME_HEADER_LEN + body.len()) as u32;
+                let mut ret = vec![FRAME_MAGIC];
+                ret.extend_from_slice(&len.to_be_bytes());
+                ret.push(*channel_id);
+                ret.push(*reliable as u8);
+                ret.extend_from_slice(&body);
+                ret
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::photon::{OperationResponse, Value};
+
+    use super::*;
+
+    fn message() -> Frame {
+        Frame::Message { channel_id: 2, reliable: true,
+            msg: PhotonCommand::OperationResponse(OperationResponse::new(1, 0,
+                vec![(3, Some(Value::Byte(4)))])) }
+    }
+
+    #[test]
+    fn pings_round_trip() {
+        for (sender, server_time) in [(Sender::Client, None),
+                (Sender::Server, Some(5))] {
+            let ping = Ping { server_time, client_time: 6 };
+            let buf = Frame::Ping(ping).serialize().unwrap();
+            assert_eq!(Frame::len(&buf, sender).unwrap(), Some(buf.len()));
+            let Frame::Ping(decoded) = Frame::deserialize(&buf, sender)
+                    .unwrap() else {
+                panic!("not a ping");
+            };
+            assert_eq!(decoded, ping);
+        }
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let buf = message().serialize().unwrap();
+        assert_eq!(Frame::len(&buf, Sender::Client).unwrap(), Some(buf.len()));
+        let decoded = Frame::deserialize(&buf, Sender::Client).unwrap();
+        assert!(matches!(decoded,
+            Frame::Message { channel_id: 2, reliable: true, .. }));
+        assert_eq!(decoded.serialize().unwrap(), buf);
+    }
+
+    #[test]
+    fn partial_frames_have_no_len_yet() {
+        let buf = message().serialize().unwrap();
+        assert_eq!(Frame::len(&[], Sender::Client).unwrap(), None);
+        assert_eq!(Frame::len(&buf[..3], Sender::Client).unwrap(), None);
+    }
+
+    #[test]
+    fn frames_shorter_than_their_header_are_rejected() {
+        let mut buf = message().serialize().unwrap();
+        buf[1..5].copy_from_slice(&6u32.to_be_bytes());
+        assert!(Frame::len(&buf, Sender::Client).is_err());
+        assert!(Frame::len(&[0], Sender::Client).is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::error::DecodeError;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CommandType {
     None,
@@ -18,10 +20,12 @@ impl Default for CommandType {
     }
 }
 
-impl From<u8> for CommandType {
-    fn from(val: u8) -> Self {
-        match val {
-            0  => Self::None, 
+impl TryFrom<u8> for CommandType {
+    type Error = DecodeError;
+
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        Ok(match val {
+            0  => Self::None,
             1  => Self::Ack,
             2  => Self::Connect,
             3  => Self::VerifyConnect,
@@ -32,8 +36,9 @@ impl From<u8> for CommandType {
             8  => Self::Fragmented,
             12 => Self::ServerTime,
 
-            _ => panic!("{:x} is not a valid command tye", val)
-        }
+            _ => return Err(DecodeError::InvalidValue { offset: 0,
+                field: "command type", value: val as u32 })
+        })
     }
 }
 
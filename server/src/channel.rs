@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+
+use packets::command::Command;
+
+/// How far ahead of the next expected sequence number reliable commands are
+/// buffered. Anything beyond that is dropped unacknowledged and resent later.
+const RELIABLE_WINDOW: u32 = 512;
+/// Unreliable commands waiting for the reliable command they were sent after.
+const MAX_HELD_UNRELIABLE: usize = 128;
+
+#[derive(Debug, Default)]
+pub struct Channel {
+    outgoing_reliable_seq: u32,
+    outgoing_unreliable_seq: u32,
+    incoming_reliable_seq: u32,
+    incoming_unreliable_seq: u32,
+    incoming: BTreeMap<u32, Command>,
+    held_unreliable: Vec<Command>
+}
+
+impl Channel {
+    pub fn next_reliable_seq(&mut self) -> u32 {
+        self.outgoing_reliable_seq = self.outgoing_reliable_seq.wrapping_add(1);
+        self.outgoing_unreliable_seq = 0;
+        self.outgoing_reliable_seq
+    }
+
+    /// Returns the reliable sequence number an unreliable command is sent
+    /// after and its own sequence number.
+    pub fn next_unreliable_seq(&mut self) -> (u32, u32) {
+        self.outgoing_unreliable_seq =
+            self.outgoing_unreliable_seq.wrapping_add(1);
+        (self.outgoing_reliable_seq, self.outgoing_unreliable_seq)
+    }
+
+    /// Buffers an incoming reliable command and returns every command that
+    /// can now be delivered in sequence. Duplicates are accepted but never
+    /// delivered twice, `None` means the command is outside of the window and
+    /// must not be acknowledged.
+    pub fn receive_reliable(&mut self, cmd: Command) -> Option<Vec<Command>> {
+        let seq_num = cmd.header.reliable_seq_num;
+        if seq_num <= self.incoming_reliable_seq {
+            return Some(Vec::new());
+        }
+        if seq_num - self.incoming_reliable_seq > RELIABLE_WINDOW {
+            return None;
+        }
+        self.incoming.entry(seq_num).or_insert(cmd);
+
+        let mut ret = Vec::new();
+        while let Some(cmd) = self.incoming.remove(
+                &(self.incoming_reliable_seq + 1)) {
+            self.incoming_reliable_seq += 1;
+            self.incoming_unreliable_seq = 0;
+            ret.push(cmd);
+            ret.extend(self.release_unreliable());
+        }
+        Some(ret)
+    }
+
+    /// Sequences an incoming unreliable command like eNet does: it's only
+    /// delivered if it's newer than everything already delivered after the
+    /// same reliable command. Commands sent after a reliable command that
+    /// didn't arrive yet are held back until it does.
+    pub fn receive_unreliable(&mut self, cmd: Command) -> Vec<Command> {
+        let reliable_seq = cmd.header.reliable_seq_num;
+        if reliable_seq > self.incoming_reliable_seq {
+            if self.held_unreliable.len() == MAX_HELD_UNRELIABLE {
+                self.held_unreliable.remove(0);
+            }
+            self.held_unreliable.push(cmd);
+            return Vec::new();
+        }
+
+        if self.is_stale(&cmd) {
+            return Vec::new();
+        }
+        self.incoming_unreliable_seq = cmd.header.unreliable_seq_num
+            .unwrap_or_default();
+        vec![cmd]
+    }
+
+    fn is_stale(&self, cmd: &Command) -> bool {
+        cmd.header.reliable_seq_num < self.incoming_reliable_seq ||
+            cmd.header.unreliable_seq_num.unwrap_or_default() <=
+                self.incoming_unreliable_seq
+    }
+
+    fn release_unreliable(&mut self) -> Vec<Command> {
+        let (mut ready, held): (Vec<Command>, Vec<Command>) =
+            std::mem::take(&mut self.held_unreliable).into_iter()
+                .partition(|x| x.header.reliable_seq_num <=
+                    self.incoming_reliable_seq);
+        self.held_unreliable = held;
+        ready.sort_by_key(|x| x.header.unreliable_seq_num);
+
+        let mut ret = Vec::new();
+        for cmd in ready {
+            if !self.is_stale(&cmd) {
+                self.incoming_unreliable_seq = cmd.header.unreliable_seq_num
+                    .unwrap_or_default();
+                ret.push(cmd);
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use packets::header::{CommandHeader, FLAG_RELIABLE};
+    use packets::payload::{CommandPayload, Ping};
+    use packets::typ::CommandType;
+
+    use super::*;
+
+    fn reliable(seq_num: u32) -> Command {
+        let header = CommandHeader::new(CommandType::Ping, 0, FLAG_RELIABLE,
+            seq_num);
+        Command::new(header, CommandPayload::Ping(Ping::new()))
+    }
+
+    fn unreliable(reliable_seq: u32, seq_num: u32) -> Command {
+        let header = CommandHeader::new(CommandType::Ping, 0, 0, reliable_seq)
+            .make_unreliable(seq_num);
+        Command::new(header, CommandPayload::Ping(Ping::new()))
+    }
+
+    fn unreliable_seq_nums(cmds: Vec<Command>) -> Vec<u32> {
+        cmds.iter().map(|x| x.header.unreliable_seq_num.unwrap()).collect()
+    }
+
+    fn seq_nums(cmds: Option<Vec<Command>>) -> Option<Vec<u32>> {
+        cmds.map(|x| x.iter().map(|x| x.header.reliable_seq_num).collect())
+    }
+
+    #[test]
+    fn reliable_commands_are_delivered_in_order() {
+        let mut channel = Channel::default();
+        assert_eq!(seq_nums(channel.receive_reliable(reliable(2))),
+            Some(vec![]));
+        assert_eq!(seq_nums(channel.receive_reliable(reliable(3))),
+            Some(vec![]));
+        assert_eq!(seq_nums(channel.receive_reliable(reliable(1))),
+            Some(vec![1, 2, 3]));
+        assert_eq!(seq_nums(channel.receive_reliable(reliable(4))),
+            Some(vec![4]));
+    }
+
+    #[test]
+    fn duplicates_are_acknowledged_but_not_delivered() {
+        let mut channel = Channel::default();
+        assert_eq!(seq_nums(channel.receive_reliable(reliable(1))),
+            Some(vec![1]));
+        assert_eq!(seq_nums(channel.receive_reliable(reliable(1))),
+            Some(vec![]));
+
+        // Buffered, but not delivered yet.
+        channel.receive_reliable(reliable(3));
+        assert_eq!(seq_nums(channel.receive_reliable(reliable(3))),
+            Some(vec![]));
+        assert_eq!(seq_nums(channel.receive_reliable(reliable(2))),
+            Some(vec![2, 3]));
+    }
+
+    #[test]
+    fn commands_beyond_the_window_are_not_acknowledged() {
+        let mut channel = Channel::default();
+        assert!(channel.receive_reliable(reliable(RELIABLE_WINDOW + 1))
+            .is_none());
+        let last = reliable(RELIABLE_WINDOW);
+        assert_eq!(seq_nums(channel.receive_reliable(last)), Some(vec![]));
+    }
+    #[test]
+    fn stale_unreliable_commands_are_dropped() {
+        let mut channel = Channel::default();
+        assert_eq!(unreliable_seq_nums(channel.receive_unreliable(
+            unreliable(0, 2))), vec![2]);
+        assert!(channel.receive_unreliable(unreliable(0, 1)).is_empty());
+        assert!(channel.receive_unreliable(unreliable(0, 2)).is_empty());
+        assert_eq!(unreliable_seq_nums(channel.receive_unreliable(
+            unreliable(0, 3))), vec![3]);
+
+        // A newer reliable command starts the sequence over.
+        channel.receive_reliable(reliable(1));
+        assert_eq!(unreliable_seq_nums(channel.receive_unreliable(
+            unreliable(1, 1))), vec![1]);
+        assert!(channel.receive_unreliable(unreliable(0, 4)).is_empty());
+    }
+
+    #[test]
+    fn unreliable_commands_wait_for_their_reliable_command() {
+        let mut channel = Channel::default();
+        assert!(channel.receive_unreliable(unreliable(1, 2)).is_empty());
+        assert!(channel.receive_unreliable(unreliable(1, 1)).is_empty());
+
+        let delivered = channel.receive_reliable(reliable(1)).unwrap();
+        assert_eq!(delivered.len(), 3);
+        assert_eq!(delivered[0].header.unreliable_seq_num, None);
+        assert_eq!(unreliable_seq_nums(delivered[1..].to_vec()), vec![1, 2]);
+    }
+}
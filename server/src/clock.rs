@@ -0,0 +1,16 @@
+use std::sync::OnceLock;
+use std::time::Instant;
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Starts the clock, so it counts from when the server came up instead of
+/// from the first datagram.
+pub fn start() {
+    START.get_or_init(Instant::now);
+}
+
+/// Milliseconds since the server started. Wraps after ~49 days, so compare
+/// timestamps with `wrapping_sub`.
+pub fn now() -> u32 {
+    START.get_or_init(Instant::now).elapsed().as_millis() as u32
+}
@@ -0,0 +1,125 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Which part of a Photon deployment a listener plays. Clients find the
+/// master through the name server and get sent on to a game server from
+/// there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    NameServer,
+    Master,
+    Game
+}
+
+impl Role {
+    /// The port Photon clients expect the role on.
+    pub fn default_port(&self, transport: Transport) -> u16 {
+        match (self, transport) {
+            (Self::NameServer, Transport::Udp) => 5058,
+            (Self::Master, Transport::Udp) => 5055,
+            (Self::Game, Transport::Udp) => 5056,
+            (Self::NameServer, Transport::Tcp) => 4533,
+            (Self::Master, Transport::Tcp) => 4530,
+            (Self::Game, Transport::Tcp) => 4531
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name_server" => Ok(Self::NameServer),
+            "master" => Ok(Self::Master),
+            "game" => Ok(Self::Game),
+            _ => Err(format!("unknown role {:?}", s))
+        }
+    }
+}
+
+/// Photon clients fall back to TCP where UDP is blocked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp
+}
+
+/// An address to listen on and the role served there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoint {
+    pub role: Role,
+    pub transport: Transport,
+    pub addr: SocketAddr
+}
+
+impl Endpoint {
+    /// Listens on every address of the host, with one socket for IPv4 and
+    /// one for IPv6 clients.
+    pub fn any(role: Role, transport: Transport) -> [Self; 2] {
+        [Ipv4Addr::UNSPECIFIED.into(), Ipv6Addr::UNSPECIFIED.into()]
+            .map(|ip: IpAddr| Self { role, transport,
+                addr: SocketAddr::new(ip, role.default_port(transport)) })
+    }
+}
+
+/// Parses `<role>[/tcp]=<address>`, e.g. `master=0.0.0.0:5055` or
+/// `game/tcp=[::1]:4531`.
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (role, addr) = s.split_once('=')
+            .ok_or(format!("expected <role>=<address>, got {:?}", s))?;
+        let (role, transport) = match role.strip_suffix("/tcp") {
+            Some(role) => (role, Transport::Tcp),
+            None => (role, Transport::Udp)
+        };
+        Ok(Self {
+            role: role.parse()?,
+            transport,
+            addr: addr.parse().map_err(|e| format!("{:?}: {}", addr, e))?
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Every address a listener is bound to. IPv6 addresses only take IPv6
+    /// clients, IPv4 ones need an endpoint of their own.
+    pub endpoints: Vec<Endpoint>,
+    /// Largest datagram accepted, anything bigger is dropped.
+    pub max_datagram_size: usize,
+    /// Largest TCP frame accepted, the connection is closed for bigger ones.
+    pub max_frame_size: usize,
+    /// How often resends are queued and the commands queued for each peer
+    /// are sent.
+    pub tick: Duration,
+    /// Peers connected at once over all listeners together, further Connects
+    /// are turned away.
+    pub max_peers: usize,
+    /// How long a peer may stay silent before it's disconnected.
+    pub peer_timeout: Duration,
+    /// How long to wait without sending anything reliable before pinging a
+    /// peer, which keeps its round trip time up to date.
+    pub ping_interval: Duration
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            endpoints: [Transport::Udp, Transport::Tcp].into_iter()
+                .flat_map(|transport| [Role::NameServer, Role::Master,
+                    Role::Game].map(|role| Endpoint::any(role, transport)))
+                .flatten()
+                .collect(),
+            max_datagram_size: 1500,
+            max_frame_size: 0x80000,
+            tick: Duration::from_millis(10),
+            max_peers: 1024,
+            peer_timeout: Duration::from_secs(10),
+            ping_interval: Duration::from_secs(1)
+        }
+    }
+}
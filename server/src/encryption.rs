@@ -0,0 +1,185 @@
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use hmac::{Hmac, Mac};
+use packets::datagram::{Datagram, HEADER_LEN, IV_LEN};
+use packets::error::{DecodeError, EncodeError};
+use packets::photon::{Encrypted, PhotonCommand};
+use sha2::{Digest, Sha256};
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+const BLOCK_LEN: usize = 16;
+const HMAC_LEN: usize = 32;
+/// Length of the secrets of datagram encryption, one AES-256 key each.
+pub const SECRET_LEN: usize = 32;
+/// Most bytes an encrypted datagram is longer than the plain one.
+pub const OVERHEAD: usize = IV_LEN + BLOCK_LEN + HMAC_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    NoKey,
+    Truncated,
+    BadMac,
+    BadPadding,
+    Malformed(DecodeError),
+    Unencodable(EncodeError)
+}
+
+/// Encrypts whole datagrams like the Photon client does in datagram
+/// encryption mode: everything after the header is encrypted with AES-CBC
+/// under a fresh IV, and the header, IV and ciphertext are authenticated with
+/// an HMAC-SHA256 appended to the datagram. Both keys are secrets the peer
+/// got from us with the response to its AuthenticateOnce.
+#[derive(Clone)]
+pub struct DatagramCipher {
+    key: [u8; SECRET_LEN],
+    hmac_key: [u8; SECRET_LEN]
+}
+
+impl std::fmt::Debug for DatagramCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DatagramCipher[ .. ]")
+    }
+}
+
+impl DatagramCipher {
+    /// Uses the secrets as they are, like the client does.
+    pub fn new(encryption_secret: &[u8; SECRET_LEN],
+            hmac_secret: &[u8; SECRET_LEN]) -> Self {
+        Self { key: *encryption_secret, hmac_key: *hmac_secret }
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.hmac_key).unwrap()
+    }
+
+    /// Encrypts the datagram's commands under a fresh IV and serializes it.
+    /// The header stays readable, so the peer can be looked up before
+    /// decrypting.
+    pub fn encrypt(&self, mut datagram: Datagram) -> Vec<u8> {
+        let iv: [u8; IV_LEN] = rand::random();
+        let ciphertext = Aes256CbcEnc::new(&self.key.into(), &iv.into())
+            .encrypt_padded_vec_mut::<Pkcs7>(&datagram.serialize_cmds());
+
+        datagram.enable_encryption(iv);
+        let mut ret = datagram.serialize();
+        ret.extend_from_slice(&ciphertext);
+        let mut mac = self.mac();
+        mac.update(&ret);
+        ret.extend_from_slice(&mac.finalize().into_bytes());
+        ret
+    }
+
+    /// Authenticates and decrypts a datagram and decodes its commands.
+    pub fn decrypt(&self, datagram: &[u8]) -> Result<Datagram, CryptoError> {
+        if datagram.len() < HEADER_LEN + IV_LEN + BLOCK_LEN + HMAC_LEN {
+            return Err(CryptoError::Truncated);
+        }
+        let (data, tag) = datagram.split_at(datagram.len() - HMAC_LEN);
+        let mut mac = self.mac();
+        mac.update(data);
+        mac.verify_slice(tag).map_err(|_| CryptoError::BadMac)?;
+
+        let (header, ciphertext) = data.split_at(HEADER_LEN + IV_LEN);
+        let mut ret = Datagram::try_from(header)
+            .map_err(CryptoError::Malformed)?;
+        let plain = Aes256CbcDec::new(&self.key.into(),
+                header[HEADER_LEN..].into())
+            .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+            .map_err(|_| CryptoError::BadPadding)?;
+
+        ret.decode_cmds(&plain).map_err(|e|
+            CryptoError::Malformed(e.shift(HEADER_LEN + IV_LEN)))?;
+        Ok(ret)
+    }
+}
+
+/// Encrypts the bodies of single messages like Photon's payload encryption:
+/// AES-CBC with a zero IV, keyed with the SHA256 of the secret from
+/// InitEncryption. The magic and message type stay readable.
+#[derive(Clone)]
+pub struct PayloadCipher {
+    key: [u8; 32]
+}
+
+impl std::fmt::Debug for PayloadCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PayloadCipher[ .. ]")
+    }
+}
+
+impl PayloadCipher {
+    const MAGIC: u8 = 0xf3;
+    const IV: [u8; IV_LEN] = [0; IV_LEN];
+
+    pub fn new(secret: &[u8]) -> Self {
+        Self { key: Sha256::digest(secret).into() }
+    }
+
+    pub fn encrypt(&self, msg: &PhotonCommand)
+            -> Result<PhotonCommand, EncodeError> {
+        let plain = msg.serialize()?;
+        let msg_type = plain[1].into();
+        let ciphertext = Aes256CbcEnc::new(&self.key.into(), &Self::IV.into())
+            .encrypt_padded_vec_mut::<Pkcs7>(&plain[2..]);
+        Ok(PhotonCommand::Encrypted(Encrypted::new(msg_type, ciphertext)))
+    }
+
+    pub fn decrypt(&self, msg: &Encrypted)
+            -> Result<PhotonCommand, CryptoError> {
+        let plain = Aes256CbcDec::new(&self.key.into(), &Self::IV.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(msg.bytes())
+            .map_err(|_| CryptoError::BadPadding)?;
+
+        let mut buf = vec![Self::MAGIC, msg.msg_type().into()];
+        buf.extend_from_slice(&plain);
+        PhotonCommand::try_from(&*buf).map_err(CryptoError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use packets::command::Command;
+    use packets::datagram::AuxillaryProperty;
+    use packets::header::CommandHeader;
+    use packets::payload::{CommandPayload, Ping};
+    use packets::typ::CommandType;
+
+    use super::*;
+
+    fn datagram() -> Datagram {
+        let header = CommandHeader::new(CommandType::Ping, 0xff, 1, 1);
+        Datagram::new(0x1234, 1, 2, vec![Command::new(header,
+            CommandPayload::Ping(Ping::new()))])
+    }
+
+    #[test]
+    fn datagrams_round_trip() {
+        let cipher = DatagramCipher::new(&[1; SECRET_LEN], &[2; SECRET_LEN]);
+        let plain = datagram().serialize();
+        let buf = cipher.encrypt(datagram());
+        assert!(Datagram::is_encrypted(&buf));
+
+        let mut decrypted = cipher.decrypt(&buf).unwrap();
+        assert_eq!(decrypted.cmds.len(), 1);
+        decrypted.aux_property = AuxillaryProperty::Nothing;
+        decrypted.iv = None;
+        assert_eq!(decrypted.serialize(), plain);
+    }
+
+    #[test]
+    fn tampered_datagrams_are_rejected() {
+        let cipher = DatagramCipher::new(&[1; SECRET_LEN], &[2; SECRET_LEN]);
+        let mut buf = cipher.encrypt(datagram());
+        buf[HEADER_LEN] ^= 1;
+        assert_eq!(cipher.decrypt(&buf).unwrap_err(), CryptoError::BadMac);
+
+        let buf = cipher.encrypt(datagram());
+        let other = DatagramCipher::new(&[1; SECRET_LEN], &[3; SECRET_LEN]);
+        assert_eq!(other.decrypt(&buf).unwrap_err(), CryptoError::BadMac);
+        assert_eq!(cipher.decrypt(&buf[..HEADER_LEN]).unwrap_err(),
+            CryptoError::Truncated);
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use packets::header::CommandHeader;
+
+/// Largest message a peer may send us in fragments.
+const MAX_MESSAGE_LEN: u32 = 0x10_0000;
+/// Bytes of incomplete messages buffered per peer at once.
+const MAX_BUFFERED_LEN: usize = 0x40_0000;
+const MAX_PACKAGES: usize = 16;
+/// Milliseconds an incomplete message is kept after its first fragment.
+const PACKAGE_TIMEOUT: u32 = 10_000;
+/// Photon fills every fragment but the last up to the MTU, so a message may
+/// be split into at most as many fragments as it has bytes of this. Without
+/// a limit a peer could send a whole message one byte at a time.
+const MIN_FRAGMENT_LEN: u32 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentError {
+    Malformed,
+    TooLarge,
+    TooManyPackages,
+    BufferFull,
+    Inconsistent,
+    OutOfBounds,
+    Duplicate,
+    Overlapping
+}
+
+/// The fragments of one message. Only what arrived is buffered, the message
+/// is put together once all of it did.
+#[derive(Debug)]
+struct Package {
+    fragment_count: u32,
+    total_len: usize,
+    /// Fragment numbers received so far.
+    nums: BTreeSet<u32>,
+    /// Fragments by their offset, none of them overlap.
+    fragments: BTreeMap<usize, Vec<u8>>,
+    received_len: usize,
+    started: u32
+}
+
+impl Package {
+    fn insert(&mut self, num: u32, offset: usize, bytes: &[u8])
+            -> Result<(), FragmentError> {
+        if self.nums.contains(&num) {
+            return Err(FragmentError::Duplicate);
+        }
+        // Since the fragments don't overlap, the one starting last before
+        // this one ends is the only one that can.
+        let end = offset + bytes.len();
+        let previous = self.fragments.range(..end).next_back();
+        if previous.is_some_and(|(start, x)| start + x.len() > offset) {
+            return Err(FragmentError::Overlapping);
+        }
+
+        self.nums.insert(num);
+        self.fragments.insert(offset, Vec::from(bytes));
+        self.received_len += bytes.len();
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.nums.len() as u32 == self.fragment_count &&
+            self.received_len == self.total_len
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.fragments.into_values().flatten().collect()
+    }
+}
+
+/// Reassembles the fragmented messages of a single peer.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    packages: BTreeMap<(u8, u32), Package>,
+    buffered_len: usize
+}
+
+impl Reassembler {
+    /// Adds a fragment and returns the whole message once its last fragment
+    /// arrived.
+    pub fn insert(&mut self, header: &CommandHeader, bytes: &[u8], now: u32)
+            -> Result<Option<Vec<u8>>, FragmentError> {
+        let (Some(start_seq_num), Some(fragment_count), Some(fragment_num),
+                Some(total_len), Some(offset)) = (header.start_seq_num,
+                header.fragment_count, header.fragment_num, header.total_len,
+                header.fragment_offset) else {
+            return Err(FragmentError::Malformed);
+        };
+        if fragment_count == 0 || fragment_num >= fragment_count ||
+                bytes.is_empty() ||
+                fragment_count > total_len.div_ceil(MIN_FRAGMENT_LEN) {
+            return Err(FragmentError::Malformed);
+        }
+        if total_len > MAX_MESSAGE_LEN {
+            return Err(FragmentError::TooLarge);
+        }
+        let offset = offset as usize;
+        if offset + bytes.len() > total_len as usize {
+            return Err(FragmentError::OutOfBounds);
+        }
+        if self.buffered_len + bytes.len() > MAX_BUFFERED_LEN {
+            return Err(FragmentError::BufferFull);
+        }
+
+        let key = (header.channel_id, start_seq_num);
+        if !self.packages.contains_key(&key) {
+            if self.packages.len() == MAX_PACKAGES {
+                return Err(FragmentError::TooManyPackages);
+            }
+            self.packages.insert(key, Package {
+                fragment_count,
+                total_len: total_len as usize,
+                nums: BTreeSet::new(),
+                fragments: BTreeMap::new(),
+                received_len: 0,
+                started: now
+            });
+        }
+
+        let package = self.packages.get_mut(&key).unwrap();
+        if package.fragment_count != fragment_count ||
+                package.total_len != total_len as usize {
+            return Err(FragmentError::Inconsistent);
+        }
+        package.insert(fragment_num, offset, bytes)?;
+        self.buffered_len += bytes.len();
+
+        if !package.is_complete() {
+            return Ok(None);
+        }
+        let package = self.packages.remove(&key).unwrap();
+        self.buffered_len -= package.received_len;
+        Ok(Some(package.into_bytes()))
+    }
+
+    /// Drops every message that didn't complete in time and returns how many
+    /// were dropped.
+    pub fn expire(&mut self, now: u32) -> usize {
+        let before = self.packages.len();
+        self.packages.retain(|_, x|
+            now.wrapping_sub(x.started) < PACKAGE_TIMEOUT);
+        self.buffered_len = self.packages.values().map(|x| x.received_len)
+            .sum();
+        before - self.packages.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use packets::header::FLAG_RELIABLE;
+    use packets::typ::CommandType;
+
+    use super::*;
+
+    const LEN: usize = MIN_FRAGMENT_LEN as usize;
+
+    fn fragment(count: u32, num: u32, offset: usize, total_len: usize)
+            -> CommandHeader {
+        CommandHeader::new(CommandType::Reliable, 0, FLAG_RELIABLE, 1 + num)
+            .make_fragmented(1, count, num, offset as u32, total_len as u32)
+    }
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|x| x as u8).collect()
+    }
+
+    #[test]
+    fn fragments_reassemble_in_any_order() {
+        let msg = message(3 * LEN);
+        let mut fragments = Reassembler::default();
+        for num in [2, 0] {
+            let offset = num * LEN;
+            assert_eq!(fragments.insert(&fragment(3, num as u32, offset,
+                msg.len()), &msg[offset..offset + LEN], 0), Ok(None));
+        }
+        assert_eq!(fragments.insert(&fragment(3, 1, LEN, msg.len()),
+            &msg[LEN..2 * LEN], 0), Ok(Some(msg)));
+        assert_eq!(fragments.buffered_len, 0);
+    }
+
+    #[test]
+    fn duplicate_fragments_are_rejected() {
+        let msg = message(2 * LEN);
+        let mut fragments = Reassembler::default();
+        let header = fragment(2, 0, 0, msg.len());
+        fragments.insert(&header, &msg[..LEN], 0).unwrap();
+        assert_eq!(fragments.insert(&header, &msg[..LEN], 0),
+            Err(FragmentError::Duplicate));
+    }
+
+    #[test]
+    fn overlapping_fragments_are_rejected() {
+        let msg = message(2 * LEN);
+        let mut fragments = Reassembler::default();
+        fragments.insert(&fragment(2, 1, LEN, msg.len()), &msg[LEN..], 0)
+            .unwrap();
+        assert_eq!(fragments.insert(&fragment(2, 0, 1, msg.len()),
+            &msg[1..LEN + 1], 0), Err(FragmentError::Overlapping));
+        assert_eq!(fragments.insert(&fragment(2, 0, 0, msg.len()),
+            &msg[..LEN], 0), Ok(Some(msg)));
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let mut fragments = Reassembler::default();
+        let total_len = MAX_MESSAGE_LEN as usize + 1;
+        assert_eq!(fragments.insert(&fragment(2, 0, 0, total_len), b"ab", 0),
+            Err(FragmentError::TooLarge));
+        assert_eq!(fragments.insert(&fragment(1, 0, 3, 4), b"de", 0),
+            Err(FragmentError::OutOfBounds));
+        assert!(fragments.packages.is_empty());
+    }
+
+    #[test]
+    fn tiny_fragments_are_rejected() {
+        let mut fragments = Reassembler::default();
+        assert_eq!(fragments.insert(&fragment(3, 0, 0, 2 * LEN), b"a", 0),
+            Err(FragmentError::Malformed));
+        assert_eq!(fragments.insert(&fragment(2, 0, 0, 2 * LEN), b"", 0),
+            Err(FragmentError::Malformed));
+    }
+
+    #[test]
+    fn only_received_bytes_are_buffered() {
+        let mut fragments = Reassembler::default();
+        let total_len = MAX_MESSAGE_LEN as usize;
+        let count = MAX_MESSAGE_LEN / MIN_FRAGMENT_LEN;
+        fragments.insert(&fragment(count, 0, 0, total_len), b"ab", 0)
+            .unwrap();
+        assert_eq!(fragments.buffered_len, 2);
+        assert_eq!(fragments.expire(PACKAGE_TIMEOUT), 1);
+        assert_eq!(fragments.buffered_len, 0);
+    }
+}
@@ -0,0 +1,59 @@
+use num_bigint::{BigUint, RandBigInt};
+
+/// The 768 bit MODP group from RFC 2409 (Oakley group 1), which is what
+/// Photon clients use for InitEncryption.
+const PRIME: [u8; KEY_LEN] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x0f, 0xda, 0xa2,
+    0x21, 0x68, 0xc2, 0x34, 0xc4, 0xc6, 0x62, 0x8b, 0x80, 0xdc, 0x1c, 0xd1,
+    0x29, 0x02, 0x4e, 0x08, 0x8a, 0x67, 0xcc, 0x74, 0x02, 0x0b, 0xbe, 0xa6,
+    0x3b, 0x13, 0x9b, 0x22, 0x51, 0x4a, 0x08, 0x79, 0x8e, 0x34, 0x04, 0xdd,
+    0xef, 0x95, 0x19, 0xb3, 0xcd, 0x3a, 0x43, 0x1b, 0x30, 0x2b, 0x0a, 0x6d,
+    0xf2, 0x5f, 0x14, 0x37, 0x4f, 0xe1, 0x35, 0x6d, 0x6d, 0x51, 0xc2, 0x45,
+    0xe4, 0x85, 0xb5, 0x76, 0x62, 0x5e, 0x7e, 0xc6, 0xf4, 0x4c, 0x42, 0xe9,
+    0xa6, 0x3a, 0x36, 0x20, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+/// Photon doesn't use the group's generator 2 but 22.
+const GENERATOR: u32 = 22;
+const SECRET_BITS: u64 = 160;
+pub const KEY_LEN: usize = 96;
+
+/// Our half of a Diffie-Hellman exchange with a single peer.
+pub struct KeyExchange {
+    secret: BigUint,
+    public_key: Vec<u8>
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let prime = BigUint::from_bytes_be(&PRIME);
+        let secret = rand::thread_rng().gen_biguint(SECRET_BITS);
+        let public_key = Self::to_key(
+            &BigUint::from(GENERATOR).modpow(&secret, &prime));
+        Self { secret, public_key }
+    }
+
+    /// Big endian and padded to the length of the prime, like the client
+    /// sends its own.
+    fn to_key(val: &BigUint) -> Vec<u8> {
+        let bytes = val.to_bytes_be();
+        let mut ret = vec![0; KEY_LEN - bytes.len()];
+        ret.extend_from_slice(&bytes);
+        ret
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Returns the secret shared with the owner of `peer_key`, or `None` if
+    /// it isn't a usable public key of the group.
+    pub fn shared_secret(&self, peer_key: &[u8]) -> Option<Vec<u8>> {
+        let prime = BigUint::from_bytes_be(&PRIME);
+        let peer_key = BigUint::from_bytes_be(peer_key);
+        let one = BigUint::from(1u32);
+        if peer_key <= one || peer_key >= &prime - &one {
+            return None;
+        }
+        Some(peer_key.modpow(&self.secret, &prime).to_bytes_be())
+    }
+}
@@ -1,30 +1,74 @@
-use std::net::{UdpSocket, SocketAddr};
 use std::env::args;
 
-mod request;
-use crate::request::Request;
+use packets::command::Command;
+use packets::datagram::Datagram;
+use packets::payload::CommandPayload;
+use packets::photon::PhotonCommand;
 
-mod reply;
-use crate::reply::Reply;
-
-static mut CTR: u32 = 0;
-
-pub fn handle_request(buf: &[u8], _socket: &UdpSocket, _conn: SocketAddr) {
-    let packet = Request::from(Vec::from(buf));
-    println!("{} {:?}", unsafe { CTR }, packet);
-    unsafe { CTR += 1 };
-}
+mod channel;
+mod clock;
+mod config;
+mod encryption;
+pub use crate::config::{Config, Endpoint, Role, Transport};
+mod fragment;
+mod key_exchange;
+use crate::fragment::Reassembler;
+mod listener;
+pub use crate::listener::Listener;
+mod message;
+pub use crate::message::Message;
+mod peer;
+pub use crate::peer::{Peer, PeerState};
+mod peer_id;
+pub use crate::peer_id::{PeerIds, SharedPeerIds};
+mod reliable;
+mod session;
+pub use crate::session::Sessions;
+mod socket;
+mod stats;
+pub use crate::stats::Stats;
+mod tcp;
+pub use crate::tcp::{TcpConnection, TcpListener};
 
 pub fn parse_packets() {
     let data = std::fs::read(args().nth(1).unwrap()).unwrap();
     let lines: Vec<&[u8]> = data
         .split(|x| *x == u8::from_str_radix(&*args().nth(2).unwrap(), 16).unwrap())
         .filter(|x| x.len() > 0).collect();
+    let mut client_fragments = Reassembler::default();
+    let mut server_fragments = Reassembler::default();
     for p in lines {
         if p[0] == 0 {
-            println!("cli => srv: {:?}", Request::from(Vec::from(&p[1..])));
+            match Datagram::try_from(&p[1..]) {
+                Ok(packet) => {
+                    println!("cli => srv: {:?}", packet);
+                    reassemble(&mut client_fragments, &packet.cmds);
+                },
+                Err(e) => println!("cli => srv: malformed: {:?}", e)
+            }
         } else {
-            println!("srv => cli: {:?}", Reply::from(Vec::from(&p[1..])));
+            match Datagram::try_from(&p[1..]) {
+                Ok(packet) => {
+                    println!("srv => cli: {:?}", packet);
+                    reassemble(&mut server_fragments, &packet.cmds);
+                },
+                Err(e) => println!("srv => cli: malformed: {:?}", e)
+            }
+        }
+    }
+}
+
+fn reassemble(fragments: &mut Reassembler, cmds: &[Command]) {
+    for cmd in cmds {
+        if let Some(CommandPayload::Fragmented(p)) = &cmd.payload {
+            match fragments.insert(&cmd.header, &p.bytes, 0) {
+                Ok(Some(bytes)) => {
+                    println!("    reassembled: {:?}",
+                        PhotonCommand::try_from(&*bytes));
+                },
+                Ok(None) => {},
+                Err(e) => println!("    broken fragment: {:?}", e)
+            }
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,163 @@
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use packets::datagram::Datagram;
+use packets::payload::DisconnectReason;
+
+use crate::clock;
+use crate::config::{Config, Endpoint, Role};
+use crate::encryption::CryptoError;
+use crate::peer_id::SharedPeerIds;
+use crate::session::Sessions;
+use crate::socket;
+use crate::stats::Stats;
+
+pub struct Listener {
+    endpoint: Endpoint,
+    socket: UdpSocket,
+    config: Config,
+    sessions: Sessions,
+    stats: Stats
+}
+
+impl Listener {
+    /// Binds to one of the config's endpoints, every listener keeps its own
+    /// peers but takes their ids from `peer_ids`.
+    pub fn bind(endpoint: Endpoint, config: Config, peer_ids: SharedPeerIds)
+            -> io::Result<Self> {
+        let socket = UdpSocket::from(socket::bind(&endpoint)?);
+        socket.set_read_timeout(Some(config.tick))?;
+        clock::start();
+        Ok(Self {
+            endpoint,
+            socket,
+            sessions: Sessions::new(&config, peer_ids),
+            config,
+            stats: Stats::default()
+        })
+    }
+
+    pub fn role(&self) -> Role {
+        self.endpoint.role
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    pub fn sessions_mut(&mut self) -> &mut Sessions {
+        &mut self.sessions
+    }
+
+    /// Serves peers until `stop` is set, then tells every one of them the
+    /// server is shutting down.
+    pub fn run(&mut self, stop: &AtomicBool) {
+        // One byte more than allowed, so oversized datagrams can be told
+        // apart from ones that fill the buffer exactly.
+        let mut buf = vec![0; self.config.max_datagram_size + 1];
+        let mut last_tick = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            match self.socket.recv_from(&mut buf) {
+                Ok((amt, conn)) => self.receive(&buf[..amt], conn),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock |
+                    ErrorKind::TimedOut) => {},
+                Err(e) => println!("Failed to receive: {}", e)
+            }
+
+            if last_tick.elapsed() < self.config.tick {
+                continue;
+            }
+            last_tick = Instant::now();
+            self.sessions.tick(&self.socket, &self.config);
+            for peer in self.sessions.take_left() {
+                println!("peer 0x{:04x} ({}) left: {:?}", peer.peer_id,
+                    peer.addr, peer.disconnect_reason);
+            }
+        }
+        self.sessions.disconnect_all(DisconnectReason::Shutdown, &self.socket);
+    }
+
+    fn receive(&mut self, buf: &[u8], conn: SocketAddr) {
+        self.stats.received += 1;
+        if buf.len() > self.config.max_datagram_size {
+            self.stats.oversized += 1;
+            println!("Dropping oversized datagram from {} ({} so far)", conn,
+                self.stats.oversized);
+            return;
+        }
+        let packet = if Datagram::is_encrypted(buf) {
+            self.decrypt(buf, conn)
+        } else {
+            self.decode(buf, conn)
+        };
+        let Some(packet) = packet else {
+            return;
+        };
+        if self.sessions.get(&conn).is_some_and(|x| !x.accepts(&packet)) {
+            self.stats.rejected += 1;
+            println!("Rejecting datagram from {} with challenge 0x{:08x} and \
+                peer id 0x{:04x} ({} so far)", conn, packet.challenge,
+                packet.peer_id, self.stats.rejected);
+            return;
+        }
+        println!("{} {:?}", conn, packet);
+        for msg in self.sessions.handle(conn, packet, &self.socket) {
+            println!("{} => {:?}", conn, msg);
+        }
+    }
+
+    fn decrypt(&mut self, buf: &[u8], conn: SocketAddr)
+            -> Option<Datagram> {
+        let peer = self.sessions.get(&conn);
+        match peer.map_or(Err(CryptoError::NoKey), |x| x.decrypt(buf)) {
+            Ok(x) => Some(x),
+            Err(CryptoError::Malformed(e)) => {
+                self.stats.malformed += 1;
+                println!("Dropping malformed datagram from {}: {:?} ({} so far)",
+                    conn, e, self.stats.malformed);
+                None
+            },
+            Err(e) => {
+                self.stats.undecryptable += 1;
+                println!("Dropping encrypted datagram from {}: {:?} \
+                    ({} so far)", conn, e, self.stats.undecryptable);
+                None
+            }
+        }
+    }
+
+    fn decode(&mut self, buf: &[u8], conn: SocketAddr)
+            -> Option<Datagram> {
+        // The CRC covers the command sizes too, so a corrupted one shouldn't
+        // pass as truncation.
+        if !Datagram::crc_matches(buf) {
+            self.stats.bad_crc += 1;
+            println!("Dropping datagram with a bad CRC from {} ({} so far)",
+                conn, self.stats.bad_crc);
+            return None;
+        }
+
+        if !Datagram::is_complete(buf) {
+            self.stats.truncated += 1;
+            println!("Dropping truncated datagram of {} bytes from {} ({} so far)",
+                buf.len(), conn, self.stats.truncated);
+            return None;
+        }
+
+        match Datagram::try_from(buf) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                self.stats.malformed += 1;
+                println!("Dropping malformed datagram from {}: {:?} ({} so far)",
+                    conn, e, self.stats.malformed);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+
+use packets::photon::{Dictionary, GpType, InternalOperationRequest,
+    InternalOperationResponse, Operation, OperationResponse, PhotonCode,
+    PhotonCommand, Value};
+
+use crate::encryption::{CryptoError, PayloadCipher, SECRET_LEN};
+use crate::key_exchange::KeyExchange;
+
+const RETURN_CODE_OK: u16 = 0;
+/// Return code of internal operations the client sent garbage for.
+const RETURN_CODE_INVALID: u16 = 0xffff;
+const AUTHENTICATE_ONCE: u8 = 231;
+/// Parameter of AuthenticateOnce with the encryption the client wants.
+const ENCRYPTION_MODE: u8 = 197;
+/// Parameter of its response with what the client needs for it.
+const ENCRYPTION_DATA: u8 = 198;
+/// Keys of the encryption data.
+const ENCRYPTION_DATA_MODE: u8 = 0;
+const ENCRYPTION_DATA_SECRET: u8 = 1;
+const ENCRYPTION_DATA_HMAC_SECRET: u8 = 2;
+/// Encryption mode of AES-CBC and HMAC-SHA256 over whole datagrams.
+const DATAGRAM_ENCRYPTION: u8 = 10;
+
+/// A message of the peer that's ready for the application.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub channel_id: u8,
+    /// Whether the peer encrypted it, the response should be encrypted too.
+    pub encrypted: bool,
+    pub cmd: PhotonCommand
+}
+
+/// What became of a message the peer sent.
+#[derive(Debug)]
+pub enum Received {
+    /// It's for the application.
+    Deliver(Message),
+    /// We answered it ourselves, the reply goes back on the same channel.
+    Reply(PhotonCommand),
+    /// We answered an AuthenticateOnce asking for datagram encryption with
+    /// its secrets. The reply has to go out before encryption starts, the
+    /// peer can't decrypt anything before it got it.
+    EnableEncryption {
+        reply: PhotonCommand,
+        encryption_secret: [u8; SECRET_LEN],
+        hmac_secret: [u8; SECRET_LEN]
+    }
+}
+
+/// Everything above the transport that gluon takes care of itself, the same
+/// over UDP and TCP: InitEncryption is answered here, encrypted messages
+/// are decrypted before the application gets them and requests for datagram
+/// encryption get their secrets.
+#[derive(Debug, Default)]
+pub struct Messages {
+    /// Secret agreed on with the peer's InitEncryption.
+    pub secret: Option<Vec<u8>>,
+    payload_cipher: Option<PayloadCipher>
+}
+
+impl Messages {
+    pub fn receive(&mut self, channel_id: u8, msg: PhotonCommand)
+            -> Result<Received, CryptoError> {
+        let init_encryption: u8 = PhotonCode::InitEncryption.into();
+        Ok(match msg {
+            PhotonCommand::InternalOperationRequest(op)
+                    if op.opcode() == init_encryption => {
+                Received::Reply(self.init_encryption(&op))
+            },
+            PhotonCommand::Encrypted(encrypted) => {
+                let cmd = self.payload_cipher.as_ref()
+                    .ok_or(CryptoError::NoKey)?
+                    .decrypt(&encrypted)?;
+                match cmd {
+                    PhotonCommand::Operation(op)
+                            if Self::requests_datagram_encryption(&op) => {
+                        self.init_datagram_encryption()?
+                    },
+                    cmd => Received::Deliver(Message { channel_id,
+                        encrypted: true, cmd })
+                }
+            },
+            _ => Received::Deliver(Message { channel_id, encrypted: false,
+                cmd: msg })
+        })
+    }
+
+    /// Encrypts the body of a message with the key from InitEncryption,
+    /// which the peer needs to have sent before. The result is sent like any
+    /// other message, over UDP or TCP.
+    pub fn encrypt(&self, msg: &PhotonCommand)
+            -> Result<PhotonCommand, CryptoError> {
+        let cipher = self.payload_cipher.as_ref().ok_or(CryptoError::NoKey)?;
+        cipher.encrypt(msg).map_err(CryptoError::Unencodable)
+    }
+
+    /// Prepares the answer to one of the peer's messages, encrypted if the
+    /// message was. It goes back on the message's channel.
+    pub fn reply(&self, to: &Message, msg: PhotonCommand)
+            -> Result<PhotonCommand, CryptoError> {
+        if to.encrypted {
+            self.encrypt(&msg)
+        } else {
+            Ok(msg)
+        }
+    }
+
+    fn requests_datagram_encryption(op: &Operation) -> bool {
+        op.opcode() == AUTHENTICATE_ONCE &&
+            op.parameter(ENCRYPTION_MODE) ==
+                Some(&Value::Byte(DATAGRAM_ENCRYPTION))
+    }
+
+    /// Picks the secrets for datagram encryption and sends them to the peer
+    /// in a response encrypted with the key from InitEncryption. gluon has no
+    /// authentication of its own, so that's all the response holds.
+    fn init_datagram_encryption(&self) -> Result<Received, CryptoError> {
+        let encryption_secret: [u8; SECRET_LEN] = rand::random();
+        let hmac_secret: [u8; SECRET_LEN] = rand::random();
+        let byte = |x| Some(Value::Byte(x));
+        let data = Dictionary {
+            key_type: Some(GpType::Byte),
+            value_type: None,
+            entries: BTreeMap::from([
+                (byte(ENCRYPTION_DATA_MODE), byte(DATAGRAM_ENCRYPTION)),
+                (byte(ENCRYPTION_DATA_SECRET),
+                    Some(Value::ByteArray(Vec::from(encryption_secret)))),
+                (byte(ENCRYPTION_DATA_HMAC_SECRET),
+                    Some(Value::ByteArray(Vec::from(hmac_secret))))
+            ])
+        };
+        let response = OperationResponse::new(AUTHENTICATE_ONCE,
+            RETURN_CODE_OK,
+            vec![(ENCRYPTION_DATA, Some(Value::Dictionary(data)))]);
+        let reply = self.encrypt(&PhotonCommand::OperationResponse(response))?;
+        Ok(Received::EnableEncryption { reply, encryption_secret,
+            hmac_secret })
+    }
+
+    /// Answers the client's half of the Diffie-Hellman exchange with ours
+    /// and keeps the resulting secret.
+    fn init_encryption(&mut self, op: &InternalOperationRequest)
+            -> PhotonCommand {
+        let opcode = PhotonCode::InitEncryption.into();
+        let exchange = KeyExchange::new();
+        let secret = match op.parameter(PhotonCode::ClientKey.into()) {
+            Some(Value::ByteArray(key)) => exchange.shared_secret(key),
+            _ => None
+        };
+
+        let response = match secret {
+            Some(secret) => {
+                self.payload_cipher = Some(PayloadCipher::new(&secret));
+                self.secret = Some(secret);
+                InternalOperationResponse::new(opcode, RETURN_CODE_OK,
+                    vec![(PhotonCode::ServerKey.into(),
+                        Some(Value::ByteArray(Vec::from(
+                            exchange.public_key()))))])
+            },
+            None => {
+                println!("Rejecting InitEncryption with an invalid client \
+                    key");
+                InternalOperationResponse::new(opcode, RETURN_CODE_INVALID,
+                    Vec::new())
+            }
+        };
+        PhotonCommand::InternalOperationResponse(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> PhotonCommand {
+        PhotonCommand::OperationResponse(OperationResponse::new(1,
+            RETURN_CODE_OK, vec![(2, Some(Value::Byte(3)))]))
+    }
+
+    #[test]
+    fn replies_are_encrypted_like_the_message() {
+        let messages = Messages { secret: None,
+            payload_cipher: Some(PayloadCipher::new(&[1; 16])) };
+        let mut to = Message { channel_id: 0, encrypted: false,
+            cmd: response() };
+        let plain = messages.reply(&to, response()).unwrap();
+        assert!(!matches!(plain, PhotonCommand::Encrypted(_)));
+
+        to.encrypted = true;
+        let PhotonCommand::Encrypted(encrypted) =
+                messages.reply(&to, response()).unwrap() else {
+            panic!("the reply isn't encrypted");
+        };
+        let decrypted = messages.payload_cipher.as_ref().unwrap()
+            .decrypt(&encrypted).unwrap();
+        assert_eq!(decrypted.serialize().unwrap(),
+            response().serialize().unwrap());
+    }
+
+    #[test]
+    fn encrypted_replies_need_a_key() {
+        let to = Message { channel_id: 0, encrypted: true, cmd: response() };
+        assert!(matches!(Messages::default().reply(&to, response()),
+            Err(CryptoError::NoKey)));
+    }
+}
@@ -0,0 +1,730 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{SocketAddr, UdpSocket};
+
+use packets::command::Command;
+use packets::crc::CRC_LEN;
+use packets::datagram::{AuxillaryProperty, Datagram, HEADER_LEN};
+use packets::error::EncodeError;
+use packets::header::{CommandHeader, FLAG_RELIABLE};
+use packets::payload::{Ack, CommandPayload, ConnectParameters, Disconnect,
+    DisconnectReason, Fragmented, Ping, Reliable, Unreliable, VerifyConnect};
+use packets::photon::PhotonCommand;
+use packets::typ::CommandType;
+
+use crate::channel::Channel;
+use crate::clock;
+use crate::config::Config;
+use crate::encryption::{self, CryptoError, DatagramCipher, SECRET_LEN};
+use crate::fragment::Reassembler;
+use crate::message::{Message, Messages, Received};
+use crate::peer_id::UNASSIGNED_PEER_ID;
+use crate::reliable::ResendQueue;
+
+const CONTROL_CHANNEL: u8 = 0xff;
+/// Photon's default MTU, used until the peer told us its own.
+const DEFAULT_MTU: u16 = 1200;
+/// Smallest MTU we can fit our commands and fragments into.
+const MIN_MTU: u16 = 576;
+const DEFAULT_WINDOW_SIZE: u32 = 0x8000;
+const MIN_WINDOW_SIZE: u32 = 0x1000;
+const MAX_WINDOW_SIZE: u32 = 0x10000;
+const DEFAULT_THROTTLE_INTERVAL: u32 = 5000;
+const DEFAULT_THROTTLE_ACCELERATION: u32 = 2;
+const DEFAULT_THROTTLE_DECELERATION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    Connecting,
+    Connected,
+    Disconnecting,
+    Gone
+}
+
+impl PeerState {
+    fn can_become(self, next: Self) -> bool {
+        matches!((self, next),
+            (Self::Connecting, Self::Connected) |
+            (Self::Connecting | Self::Connected, Self::Disconnecting) |
+            (_, Self::Gone))
+    }
+}
+
+#[derive(Debug)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub peer_id: u16,
+    pub state: PeerState,
+    pub challenge: u32,
+    /// What we agreed on with the peer's Connect.
+    pub params: ConnectParameters,
+    /// Largest MTU we agree on, the size of the largest datagram we take.
+    max_mtu: u16,
+    /// Whether the peer sends and expects a CRC with every datagram.
+    pub use_crc: bool,
+    /// The internal operations and encryption of the peer's messages.
+    pub messages: Messages,
+    cipher: Option<DatagramCipher>,
+    /// Takes over from `cipher` once what's queued was sent.
+    next_cipher: Option<DatagramCipher>,
+    /// Reliable commands sent before encryption started. Until the peer
+    /// encrypts itself it may not have the keys, so their resends stay
+    /// readable.
+    plaintext: BTreeSet<(u8, u32)>,
+    /// Whether the peer sent an encrypted datagram yet, plain ones are
+    /// accepted until then.
+    peer_encrypts: bool,
+    /// Why we disconnected the peer, `None` if it disconnected on its own.
+    pub disconnect_reason: Option<DisconnectReason>,
+    channels: BTreeMap<u8, Channel>,
+    resend_queue: ResendQueue,
+    fragments: Reassembler,
+    pending_acks: Vec<Command>,
+    outgoing: Vec<Command>,
+    last_received: u32,
+    last_reliable_sent: u32
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr, peer_id: u16, challenge: u32, max_mtu: u16)
+            -> Self {
+        Self {
+            addr,
+            peer_id,
+            state: PeerState::Connecting,
+            challenge,
+            params: Self::negotiate(ConnectParameters::default(), max_mtu),
+            max_mtu,
+            use_crc: false,
+            messages: Messages::default(),
+            cipher: None,
+            next_cipher: None,
+            plaintext: BTreeSet::new(),
+            peer_encrypts: false,
+            disconnect_reason: None,
+            channels: BTreeMap::new(),
+            resend_queue: ResendQueue::default(),
+            fragments: Reassembler::default(),
+            pending_acks: Vec::new(),
+            outgoing: Vec::new(),
+            last_received: clock::now(),
+            last_reliable_sent: clock::now()
+        }
+    }
+
+    /// Smoothed round trip time in milliseconds.
+    pub fn round_trip_time(&self) -> u32 {
+        self.resend_queue.round_trip_time()
+    }
+
+    pub fn round_trip_time_variance(&self) -> u32 {
+        self.resend_queue.round_trip_time_variance()
+    }
+
+    pub fn set_state(&mut self, next: PeerState) {
+        if self.state == next || !self.state.can_become(next) {
+            return;
+        }
+        println!("peer 0x{:04x} ({}): {:?} -> {:?}", self.peer_id, self.addr,
+            self.state, next);
+        self.state = next;
+    }
+
+    /// Whether the datagram was sent by this peer, i.e. carries the challenge
+    /// it picked when connecting and the peer id we assigned to it.
+    pub fn accepts(&self, datagram: &Datagram) -> bool {
+        let encrypted = datagram.aux_property == AuxillaryProperty::Encrypted;
+        if datagram.challenge != self.challenge ||
+                (encrypted && self.cipher.is_none()) ||
+                (!encrypted && self.peer_encrypts) {
+            return false;
+        }
+        datagram.peer_id == self.peer_id ||
+            (datagram.peer_id == UNASSIGNED_PEER_ID &&
+                self.state == PeerState::Connecting)
+    }
+
+    /// Encrypts every command queued after the next flush, keyed with the
+    /// secrets we sent the peer. What's queued until then still goes out
+    /// readable, resends included, like the reply that carries the secrets.
+    /// Once the peer encrypts too, everything is encrypted and only encrypted
+    /// datagrams are accepted.
+    pub fn enable_encryption(&mut self, encryption_secret: &[u8; SECRET_LEN],
+            hmac_secret: &[u8; SECRET_LEN]) {
+        self.next_cipher = Some(DatagramCipher::new(encryption_secret,
+            hmac_secret));
+    }
+
+    pub fn decrypt(&self, datagram: &[u8]) -> Result<Datagram, CryptoError> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(datagram),
+            None => Err(CryptoError::NoKey)
+        }
+    }
+
+    /// Processes every command of the datagram and returns the messages that
+    /// are ready for the application, in the order the peer sent them.
+    pub fn handle(&mut self, datagram: &Datagram) -> Vec<Message> {
+        self.last_received = clock::now();
+        self.use_crc = datagram.crc.is_some();
+        if datagram.aux_property == AuxillaryProperty::Encrypted {
+            // It has the keys, nothing needs to be readable anymore.
+            self.peer_encrypts = true;
+            self.plaintext.clear();
+        }
+        let mut delivered = Vec::new();
+        for cmd in &datagram.cmds {
+            let channel = self.channels.entry(cmd.header.channel_id)
+                .or_default();
+            if !cmd.header.is_reliable() {
+                // The peer syncs its clock to the send time of the datagram
+                // answering a ServerTime, so it needs an answer even if it
+                // wasn't sent reliably.
+                if cmd.header.cmd_type == CommandType::ServerTime {
+                    self.queue_ack(&cmd.header, datagram.time);
+                    continue;
+                }
+                if cmd.header.cmd_type != CommandType::Unreliable ||
+                        cmd.header.is_unsequenced() {
+                    self.dispatch(cmd.clone(), &mut delivered);
+                    continue;
+                }
+                for cmd in channel.receive_unreliable(cmd.clone()) {
+                    self.dispatch(cmd, &mut delivered);
+                }
+                continue;
+            }
+
+            let Some(ready) = channel.receive_reliable(cmd.clone()) else {
+                continue;
+            };
+            self.queue_ack(&cmd.header, datagram.time);
+            for cmd in ready {
+                self.dispatch(cmd, &mut delivered);
+            }
+        }
+        delivered
+    }
+
+    fn dispatch(&mut self, cmd: Command, delivered: &mut Vec<Message>) {
+        match cmd.payload {
+            Some(CommandPayload::Connect(connect)) => {
+                self.params = Self::negotiate(connect.params, self.max_mtu);
+                if self.params.mtu < MIN_MTU {
+                    println!("peer 0x{:04x} ({}): turning away, an MTU of {} \
+                        is too small", self.peer_id, self.addr,
+                        self.params.mtu);
+                    self.disconnect(DisconnectReason::Kicked);
+                    return;
+                }
+                self.verify_connect();
+                return;
+            },
+            Some(CommandPayload::Ack(ack)) => {
+                self.acknowledge(cmd.header.channel_id, ack);
+                return;
+            },
+            // Pings and ServerTime requests are answered by their ack, which
+            // carries our clock in the datagram's send time.
+            Some(CommandPayload::Ping(_) | CommandPayload::ServerTime(_)) => {},
+            Some(CommandPayload::Reliable(p)) => {
+                self.deliver(cmd.header.channel_id, p.into_payload(),
+                    delivered);
+            },
+            Some(CommandPayload::Unreliable(p)) => {
+                self.deliver(cmd.header.channel_id, p.into_payload(),
+                    delivered);
+            },
+            Some(CommandPayload::Fragmented(p)) => {
+                match self.fragments.insert(&cmd.header, &p.bytes,
+                        clock::now()) {
+                    Ok(Some(bytes)) => match PhotonCommand::try_from(&*bytes) {
+                        Ok(msg) => {
+                            self.deliver(cmd.header.channel_id, msg,
+                                delivered);
+                        },
+                        Err(e) => println!("peer 0x{:04x} ({}): dropping \
+                            reassembled message: {:?}", self.peer_id,
+                            self.addr, e)
+                    },
+                    Ok(None) => {},
+                    Err(e) => {
+                        println!("peer 0x{:04x} ({}): dropping {:?}: {:?}",
+                            self.peer_id, self.addr, cmd.header, e);
+                    }
+                }
+            },
+            _ => {}
+        }
+
+        if cmd.header.cmd_type == CommandType::Disconnect {
+            self.set_state(PeerState::Disconnecting);
+        } else if self.state == PeerState::Connecting {
+            self.set_state(PeerState::Connected);
+        }
+    }
+
+    /// Handles the internal operations of the protocol itself and passes
+    /// every other message on to the application.
+    fn deliver(&mut self, channel_id: u8, msg: PhotonCommand,
+            delivered: &mut Vec<Message>) {
+        match self.messages.receive(channel_id, msg) {
+            Ok(Received::Deliver(msg)) => delivered.push(msg),
+            Ok(Received::Reply(reply)) => {
+                if let Err(e) = self.send_message(channel_id, reply) {
+                    println!("peer 0x{:04x} ({}): failed to reply: {:?}",
+                        self.peer_id, self.addr, e);
+                }
+            },
+            Ok(Received::EnableEncryption { reply, encryption_secret,
+                    hmac_secret }) => {
+                match self.send_message(channel_id, reply) {
+                    Ok(()) => self.enable_encryption(&encryption_secret,
+                        &hmac_secret),
+                    Err(e) => println!("peer 0x{:04x} ({}): failed to reply: \
+                        {:?}", self.peer_id, self.addr, e)
+                }
+            },
+            Err(e) => println!("peer 0x{:04x} ({}): dropping encrypted \
+                message: {:?}", self.peer_id, self.addr, e)
+        }
+    }
+
+    fn acknowledge(&mut self, channel_id: u8, ack: Ack) {
+        let acked = self.resend_queue.acknowledge(channel_id,
+            ack.acked_seq_num, ack.send_time, clock::now());
+        self.plaintext.remove(&(channel_id, ack.acked_seq_num));
+        if let Some(cmd) = acked {
+            if cmd.header.cmd_type == CommandType::VerifyConnect {
+                self.set_state(PeerState::Connected);
+            }
+        }
+    }
+
+    /// Acknowledges a reliable command with the sent time of the datagram it
+    /// arrived in. Acks are sent with the next reply to the peer.
+    fn queue_ack(&mut self, acked: &CommandHeader, send_time: u32) {
+        let header = CommandHeader::new(CommandType::Ack, acked.channel_id, 0,
+            0);
+        let payload = Ack::new(acked.reliable_seq_num, send_time);
+        self.pending_acks.push(Command::new(header,
+            CommandPayload::Ack(payload)));
+    }
+
+    /// Takes the peer's proposal as far as we support it and falls back to
+    /// Photon's defaults for whatever it left out. The MTU is the smaller of
+    /// the peer's and ours, neither side takes larger datagrams.
+    fn negotiate(proposed: ConnectParameters, max_mtu: u16)
+            -> ConnectParameters {
+        let or = |val, default| match val {
+            0 => default,
+            val => val
+        };
+        ConnectParameters {
+            mtu: or(proposed.mtu as u32, DEFAULT_MTU as u32)
+                .min(max_mtu as u32) as u16,
+            window_size: or(proposed.window_size, DEFAULT_WINDOW_SIZE)
+                .clamp(MIN_WINDOW_SIZE, MAX_WINDOW_SIZE),
+            throttle_interval: or(proposed.throttle_interval,
+                DEFAULT_THROTTLE_INTERVAL),
+            throttle_acceleration: or(proposed.throttle_acceleration,
+                DEFAULT_THROTTLE_ACCELERATION),
+            throttle_deceleration: or(proposed.throttle_deceleration,
+                DEFAULT_THROTTLE_DECELERATION),
+            ..proposed
+        }
+    }
+
+    fn verify_connect(&mut self) {
+        // Photon sends the VerifyConnect outside of the control channel's
+        // sequence, the first sequenced command on it uses 1.
+        let header = CommandHeader::new(CommandType::VerifyConnect,
+            CONTROL_CHANNEL, FLAG_RELIABLE, 0);
+        let payload = VerifyConnect::new(self.peer_id, self.params);
+        self.queue_reliable(Command::new(header,
+            CommandPayload::VerifyConnect(payload)));
+    }
+
+    /// Tells the peer why it's being dropped. The Disconnect isn't resent, the
+    /// peer is removed right after it was flushed.
+    pub fn disconnect(&mut self, reason: DisconnectReason) {
+        if !self.state.can_become(PeerState::Disconnecting) {
+            return;
+        }
+        let seq_num = self.channels.entry(CONTROL_CHANNEL).or_default()
+            .next_reliable_seq();
+        let mut header = CommandHeader::new(CommandType::Disconnect,
+            CONTROL_CHANNEL, FLAG_RELIABLE, seq_num);
+        header.reserved = reason.into();
+        self.outgoing.push(Command::new(header,
+            CommandPayload::Disconnect(Disconnect::new(reason))));
+        self.disconnect_reason = Some(reason);
+        self.set_state(PeerState::Disconnecting);
+    }
+
+    pub fn send_reliable(&mut self, channel_id: u8, cmd_type: CommandType,
+            payload: CommandPayload) {
+        let seq_num = self.channels.entry(channel_id).or_default()
+            .next_reliable_seq();
+        let header = CommandHeader::new(cmd_type, channel_id, FLAG_RELIABLE,
+            seq_num);
+        self.queue_reliable(Command::new(header, payload));
+    }
+
+    /// Sends a message reliably, split into fragments if it doesn't fit into a
+    /// single datagram of the peer's MTU.
+    pub fn send_message(&mut self, channel_id: u8, msg: PhotonCommand)
+            -> Result<(), EncodeError> {
+        let bytes = msg.serialize()?;
+        let max_len = self.max_command_len();
+        let reliable = CommandHeader::new(CommandType::Reliable, channel_id,
+            FLAG_RELIABLE, 0);
+        if reliable.len() + bytes.len() <= max_len {
+            self.send_reliable(channel_id, CommandType::Reliable,
+                CommandPayload::Reliable(Reliable::new(msg)?));
+            return Ok(());
+        }
+
+        let fragment_len = max_len - reliable.make_fragmented(0, 0, 0, 0, 0)
+            .len();
+        let fragment_count = bytes.len().div_ceil(fragment_len) as u32;
+        let channel = self.channels.entry(channel_id).or_default();
+        let seq_nums: Vec<u32> = (0..fragment_count)
+            .map(|_| channel.next_reliable_seq()).collect();
+
+        for (num, chunk) in bytes.chunks(fragment_len).enumerate() {
+            let header = CommandHeader::new(CommandType::Reliable, channel_id,
+                FLAG_RELIABLE, seq_nums[num]).make_fragmented(
+                    seq_nums[0],
+                    fragment_count,
+                    num as u32,
+                    (num * fragment_len) as u32,
+                    bytes.len() as u32
+                );
+            self.queue_reliable(Command::new(header,
+                CommandPayload::Fragmented(Fragmented::new(Vec::from(chunk)))));
+        }
+        Ok(())
+    }
+
+    /// Answers one of the peer's messages, see `Messages::reply`.
+    pub fn reply(&mut self, to: &Message, msg: PhotonCommand)
+            -> Result<(), CryptoError> {
+        let msg = self.messages.reply(to, msg)?;
+        self.send_message(to.channel_id, msg)
+            .map_err(CryptoError::Unencodable)
+    }
+
+    /// Sends a message without waiting for it to be acknowledged. Messages
+    /// too large for a single datagram are sent reliably in fragments.
+    pub fn send_unreliable(&mut self, channel_id: u8, msg: PhotonCommand)
+            -> Result<(), EncodeError> {
+        let unreliable = CommandHeader::new(CommandType::Reliable, channel_id,
+            0, 0).make_unreliable(0);
+        let payload = CommandPayload::Unreliable(Unreliable::new(msg)?);
+        if unreliable.len() + payload.len() as usize > self.max_command_len() {
+            let CommandPayload::Unreliable(payload) = payload else {
+                unreachable!()
+            };
+            return self.send_message(channel_id, payload.into_payload());
+        }
+
+        let (reliable_seq, unreliable_seq) = self.channels.entry(channel_id)
+            .or_default().next_unreliable_seq();
+        let header = CommandHeader::new(CommandType::Reliable, channel_id, 0,
+            reliable_seq).make_unreliable(unreliable_seq);
+        self.outgoing.push(Command::new(header, payload));
+        Ok(())
+    }
+
+    fn max_command_len(&self) -> usize {
+        self.max_len(self.cipher.is_some())
+    }
+
+    /// How many bytes of commands fit into a datagram, which depends on
+    /// whether it's encrypted.
+    fn max_len(&self, encrypted: bool) -> usize {
+        let overhead = if encrypted {
+            encryption::OVERHEAD
+        } else if self.use_crc {
+            CRC_LEN
+        } else {
+            0
+        };
+        self.params.mtu as usize - HEADER_LEN - overhead
+    }
+
+    fn queue_reliable(&mut self, cmd: Command) {
+        self.last_reliable_sent = clock::now();
+        self.resend_queue.push(cmd.clone(), self.last_reliable_sent);
+        self.outgoing.push(cmd);
+    }
+
+    pub fn tick(&mut self, config: &Config) {
+        let now = clock::now();
+        if now.wrapping_sub(self.last_received) >=
+                config.peer_timeout.as_millis() as u32 {
+            println!("peer 0x{:04x} ({}) timed out", self.peer_id, self.addr);
+            self.disconnect(DisconnectReason::Timeout);
+            return;
+        }
+
+        // Pings are answered by the peer's ack like any reliable command,
+        // which is all we need them for.
+        if self.state == PeerState::Connected && self.resend_queue.is_empty() &&
+                now.wrapping_sub(self.last_reliable_sent) >=
+                    config.ping_interval.as_millis() as u32 {
+            self.send_reliable(CONTROL_CHANNEL, CommandType::Ping,
+                CommandPayload::Ping(Ping::new()));
+        }
+
+        let expired = self.fragments.expire(now);
+        if expired > 0 {
+            println!("peer 0x{:04x} ({}): dropped {} incomplete messages",
+                self.peer_id, self.addr, expired);
+        }
+        self.resend(now);
+    }
+
+    /// Queues every reliable command that wasn't acknowledged in time again.
+    /// Disconnects the peer once a command ran out of resends.
+    fn resend(&mut self, now: u32) {
+        match self.resend_queue.due(now) {
+            Some(cmds) => self.outgoing.extend(cmds),
+            None => {
+                println!("peer 0x{:04x} ({}) stopped acknowledging commands",
+                    self.peer_id, self.addr);
+                self.disconnect(DisconnectReason::Timeout);
+            }
+        }
+    }
+
+    /// Sends every queued command.
+    pub fn flush(&mut self, socket: &UdpSocket) {
+        for (encrypted, cmds) in self.pack() {
+            self.send(socket, cmds, encrypted);
+        }
+        if let Some(cipher) = self.next_cipher.take() {
+            self.plaintext = self.resend_queue.unacknowledged().collect();
+            self.cipher = Some(cipher);
+        }
+    }
+
+    /// Takes every queued command, acks first, packed into as few datagrams
+    /// of the peer's MTU as possible, each with whether it's encrypted.
+    fn pack(&mut self) -> Vec<(bool, Vec<Command>)> {
+        let mut cmds = std::mem::take(&mut self.pending_acks);
+        cmds.append(&mut self.outgoing);
+        let (encrypted, plain): (Vec<_>, Vec<_>) = cmds.into_iter()
+            .partition(|x| self.encrypts(x));
+
+        let mut ret = Vec::new();
+        for (encrypted, cmds) in [(false, plain), (true, encrypted)] {
+            let max_len = self.max_len(encrypted);
+            let mut datagram = Vec::new();
+            let mut len = 0;
+            for cmd in cmds {
+                let cmd_len = cmd.len() as usize;
+                if !datagram.is_empty() && (len + cmd_len > max_len ||
+                        datagram.len() == u8::MAX as usize) {
+                    ret.push((encrypted, std::mem::take(&mut datagram)));
+                    len = 0;
+                }
+                len += cmd_len;
+                datagram.push(cmd);
+            }
+            if !datagram.is_empty() {
+                ret.push((encrypted, datagram));
+            }
+        }
+        ret
+    }
+
+    fn encrypts(&self, cmd: &Command) -> bool {
+        let key = (cmd.header.channel_id, cmd.header.reliable_seq_num);
+        self.cipher.is_some() &&
+            !(cmd.header.is_reliable() && self.plaintext.contains(&key))
+    }
+
+    fn send(&self, socket: &UdpSocket, cmds: Vec<Command>, encrypted: bool) {
+        let mut datagram = Datagram::new(self.peer_id, clock::now(),
+            self.challenge, cmds);
+        let buf = match self.cipher.as_ref().filter(|_| encrypted) {
+            Some(cipher) => cipher.encrypt(datagram),
+            None => {
+                if self.use_crc {
+                    datagram.enable_crc();
+                }
+                datagram.serialize()
+            }
+        };
+        if let Err(e) = socket.send_to(&buf, self.addr) {
+            println!("Failed to send {} bytes to {}: {}", buf.len(), self.addr,
+                e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use packets::photon::{OperationResponse, Value};
+
+    use super::*;
+
+    fn peer() -> Peer {
+        Peer::new("127.0.0.1:5055".parse().unwrap(), 1, 0, DEFAULT_MTU)
+    }
+
+    fn message(len: usize) -> PhotonCommand {
+        PhotonCommand::OperationResponse(OperationResponse::new(1, 0,
+            vec![(1, Some(Value::ByteArray(vec![7; len])))]))
+    }
+
+    #[test]
+    fn large_messages_are_fragmented() {
+        let mut peer = peer();
+        peer.send_reliable(0, CommandType::Ping,
+            CommandPayload::Ping(Ping::new()));
+        peer.outgoing.clear();
+        let msg = message(4000);
+        let bytes = msg.serialize().unwrap();
+        peer.send_message(0, msg).unwrap();
+
+        let fragments = std::mem::take(&mut peer.outgoing);
+        assert!(fragments.len() > 1);
+        let mut reassembler = Reassembler::default();
+        let mut offset = 0;
+        let mut reassembled = None;
+        for (num, cmd) in fragments.iter().enumerate() {
+            let header = &cmd.header;
+            assert_eq!(header.cmd_type, CommandType::Fragmented);
+            assert_eq!(header.reliable_seq_num, 2 + num as u32);
+            assert_eq!(header.start_seq_num, Some(2));
+            assert_eq!(header.fragment_count, Some(fragments.len() as u32));
+            assert_eq!(header.fragment_num, Some(num as u32));
+            assert_eq!(header.fragment_offset, Some(offset));
+            assert_eq!(header.total_len, Some(bytes.len() as u32));
+            assert!(cmd.len() as usize <= peer.max_command_len());
+
+            let Some(CommandPayload::Fragmented(p)) = &cmd.payload else {
+                panic!("{:?} isn't a fragment", cmd);
+            };
+            offset += p.bytes.len() as u32;
+            assert!(reassembled.is_none());
+            reassembled = reassembler.insert(header, &p.bytes, 0).unwrap();
+        }
+        assert_eq!(reassembled, Some(bytes));
+        assert_eq!(peer.resend_queue.unacknowledged().count(),
+            fragments.len() + 1);
+    }
+
+    #[test]
+    fn small_messages_are_not_fragmented() {
+        let mut peer = peer();
+        peer.send_message(0, message(100)).unwrap();
+        assert_eq!(peer.outgoing.len(), 1);
+        assert_eq!(peer.outgoing[0].header.cmd_type, CommandType::Reliable);
+    }
+
+    #[test]
+    fn acks_are_packed_first() {
+        let mut peer = peer();
+        peer.send_message(0, message(100)).unwrap();
+        let acked = CommandHeader::new(CommandType::Ping, 0, FLAG_RELIABLE, 1);
+        peer.queue_ack(&acked, 0);
+
+        let datagrams = peer.pack();
+        assert_eq!(datagrams.len(), 1);
+        let cmd_types: Vec<_> = datagrams[0].1.iter()
+            .map(|x| x.header.cmd_type).collect();
+        assert_eq!(cmd_types, [CommandType::Ack, CommandType::Reliable]);
+    }
+
+    #[test]
+    fn datagrams_fit_into_the_mtu() {
+        let mut peer = peer();
+        for _ in 0..20 {
+            peer.send_message(0, message(300)).unwrap();
+        }
+
+        let datagrams = peer.pack();
+        assert!(datagrams.len() > 1);
+        let max_len = peer.max_command_len();
+        let mut count = 0;
+        for (encrypted, cmds) in datagrams {
+            assert!(!encrypted);
+            let len: u32 = cmds.iter().map(|x| x.len()).sum();
+            assert!(len as usize <= max_len);
+            count += cmds.len();
+            let datagram = Datagram::new(peer.peer_id, 0, 0, cmds);
+            assert!(datagram.serialize().len() <= DEFAULT_MTU as usize);
+        }
+        assert_eq!(count, 20);
+    }
+
+    #[test]
+    fn datagrams_hold_at_most_255_commands() {
+        let mut peer = peer();
+        peer.params.mtu = u16::MAX;
+        let acked = CommandHeader::new(CommandType::Ping, 0, FLAG_RELIABLE, 1);
+        for _ in 0..600 {
+            peer.queue_ack(&acked, 0);
+        }
+
+        let counts: Vec<_> = peer.pack().into_iter()
+            .map(|(_, cmds)| Datagram::new(peer.peer_id, 0, 0, cmds).cmd_count)
+            .collect();
+        assert_eq!(counts, [255, 255, 90]);
+    }
+
+    fn datagram(peer_id: u16, challenge: u32) -> Datagram {
+        Datagram::new(peer_id, 0, challenge, Vec::new())
+    }
+
+    #[test]
+    fn datagrams_need_the_challenge() {
+        let peer = peer();
+        assert!(peer.accepts(&datagram(1, 0)));
+        assert!(!peer.accepts(&datagram(1, 1)));
+    }
+
+    #[test]
+    fn datagrams_need_the_peer_id() {
+        let peer = peer();
+        assert!(!peer.accepts(&datagram(2, 0)));
+    }
+
+    #[test]
+    fn unassigned_peer_id_is_accepted_while_connecting() {
+        let mut peer = peer();
+        assert!(peer.accepts(&datagram(UNASSIGNED_PEER_ID, 0)));
+        peer.set_state(PeerState::Connected);
+        assert!(!peer.accepts(&datagram(UNASSIGNED_PEER_ID, 0)));
+        assert!(peer.accepts(&datagram(1, 0)));
+    }
+
+    #[test]
+    fn plain_datagrams_are_accepted_until_the_peer_encrypts() {
+        let mut peer = peer();
+        let mut encrypted = datagram(1, 0);
+        encrypted.aux_property = AuxillaryProperty::Encrypted;
+        assert!(!peer.accepts(&encrypted));
+
+        peer.cipher = Some(DatagramCipher::new(&[1; SECRET_LEN],
+            &[2; SECRET_LEN]));
+        assert!(peer.accepts(&datagram(1, 0)));
+        assert!(peer.accepts(&encrypted));
+        peer.handle(&encrypted);
+        assert!(!peer.accepts(&datagram(1, 0)));
+        assert!(peer.accepts(&encrypted));
+    }
+
+    #[test]
+    fn mtu_is_the_smaller_of_both() {
+        let mtu = |mtu| Peer::negotiate(ConnectParameters { mtu,
+            ..Default::default() }, 1500).mtu;
+        assert_eq!(mtu(0), DEFAULT_MTU);
+        assert_eq!(mtu(2000), 1500);
+        assert_eq!(mtu(1300), 1300);
+        assert_eq!(mtu(500), 500);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+
+/// Peer id the client uses until the VerifyConnect told it its own.
+pub const UNASSIGNED_PEER_ID: u16 = 0xffff;
+/// Milliseconds a released id isn't handed out again, so late datagrams of
+/// the old peer can't be mistaken for the new one's.
+const QUARANTINE: u32 = 30_000;
+
+/// Every listener allocates from the same ids, so they're unique across
+/// all of them and the peer limit holds for all of them together.
+pub type SharedPeerIds = Arc<Mutex<PeerIds>>;
+
+/// Hands out the ids peers use in their datagram headers.
+#[derive(Debug)]
+pub struct PeerIds {
+    in_use: BTreeSet<u16>,
+    quarantined: BTreeMap<u16, u32>,
+    last: u16,
+    max_peers: usize
+}
+
+impl PeerIds {
+    pub fn new(max_peers: usize) -> Self {
+        Self {
+            in_use: BTreeSet::new(),
+            quarantined: BTreeMap::new(),
+            last: 0,
+            max_peers
+        }
+    }
+
+    pub fn shared(max_peers: usize) -> SharedPeerIds {
+        Arc::new(Mutex::new(Self::new(max_peers)))
+    }
+
+    /// Returns an id that's neither in use nor quarantined, or `None` if
+    /// there are `max_peers` already or no id is left.
+    pub fn allocate(&mut self, now: u32) -> Option<u16> {
+        if self.in_use.len() >= self.max_peers {
+            return None;
+        }
+        self.quarantined.retain(|_, x| now.wrapping_sub(*x) < QUARANTINE);
+
+        for _ in 0..UNASSIGNED_PEER_ID {
+            self.last = match self.last {
+                x if x >= UNASSIGNED_PEER_ID - 1 => 1,
+                x => x + 1
+            };
+            if !self.in_use.contains(&self.last) &&
+                    !self.quarantined.contains_key(&self.last) {
+                self.in_use.insert(self.last);
+                return Some(self.last);
+            }
+        }
+        None
+    }
+
+    pub fn release(&mut self, peer_id: u16, now: u32) {
+        if self.in_use.remove(&peer_id) {
+            self.quarantined.insert(peer_id, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn released_ids_are_quarantined() {
+        let mut ids = PeerIds::new(usize::MAX);
+        let all: Vec<u16> = std::iter::from_fn(|| ids.allocate(0)).collect();
+        assert_eq!(all.len(), UNASSIGNED_PEER_ID as usize - 1);
+        assert!(!all.contains(&0) && !all.contains(&UNASSIGNED_PEER_ID));
+
+        ids.release(5, 1);
+        assert_eq!(ids.allocate(QUARANTINE), None);
+        assert_eq!(ids.allocate(QUARANTINE + 1), Some(5));
+        assert_eq!(ids.allocate(QUARANTINE + 1), None);
+    }
+
+    #[test]
+    fn ids_are_not_reused_while_others_are_free() {
+        let mut ids = PeerIds::new(usize::MAX);
+        let first = ids.allocate(0).unwrap();
+        ids.release(first, 0);
+        let second = ids.allocate(QUARANTINE).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn peers_are_limited() {
+        let mut ids = PeerIds::new(2);
+        let first = ids.allocate(0).unwrap();
+        ids.allocate(0).unwrap();
+        assert_eq!(ids.allocate(0), None);
+        ids.release(first, 0);
+        assert!(ids.allocate(0).is_some());
+    }
+}
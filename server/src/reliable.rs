@@ -0,0 +1,181 @@
+use packets::command::Command;
+
+/// Resends before the peer is considered lost, same as Photon's default
+/// `SentCountAllowance`.
+const MAX_RESENDS: u8 = 7;
+const INITIAL_ROUND_TRIP_TIME: u32 = 300;
+const INITIAL_ROUND_TRIP_TIME_VARIANCE: u32 = INITIAL_ROUND_TRIP_TIME / 2;
+const MIN_RESEND_TIMEOUT: u32 = 50;
+const MAX_RESEND_TIMEOUT: u32 = 5000;
+
+#[derive(Debug)]
+struct SentCommand {
+    cmd: Command,
+    sent_time: u32,
+    timeout: u32,
+    resends: u8
+}
+
+#[derive(Debug)]
+pub struct ResendQueue {
+    sent: Vec<SentCommand>,
+    round_trip_time: u32,
+    round_trip_time_variance: u32
+}
+
+impl Default for ResendQueue {
+    fn default() -> Self {
+        Self {
+            sent: Vec::new(),
+            round_trip_time: INITIAL_ROUND_TRIP_TIME,
+            round_trip_time_variance: INITIAL_ROUND_TRIP_TIME_VARIANCE
+        }
+    }
+}
+
+impl ResendQueue {
+    pub fn round_trip_time(&self) -> u32 {
+        self.round_trip_time
+    }
+
+    pub fn round_trip_time_variance(&self) -> u32 {
+        self.round_trip_time_variance
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sent.is_empty()
+    }
+
+    /// Channel and sequence number of every command waiting for its ack.
+    pub fn unacknowledged(&self) -> impl Iterator<Item = (u8, u32)> + '_ {
+        self.sent.iter().map(|x|
+            (x.cmd.header.channel_id, x.cmd.header.reliable_seq_num))
+    }
+
+    fn resend_timeout(&self) -> u32 {
+        (self.round_trip_time + 4 * self.round_trip_time_variance)
+            .clamp(MIN_RESEND_TIMEOUT, MAX_RESEND_TIMEOUT)
+    }
+
+    /// Smooths the round trip time by 1/8 and its variance by 1/4 of each
+    /// sample, like Photon and TCP do.
+    fn update_round_trip_time(&mut self, sample: u32) {
+        let rtt = self.round_trip_time as i64;
+        let delta = sample as i64 - rtt;
+        self.round_trip_time = (rtt + delta / 8) as u32;
+
+        let variance = self.round_trip_time_variance as i64;
+        self.round_trip_time_variance =
+            (variance + (delta.abs() - variance) / 4) as u32;
+    }
+
+    pub fn push(&mut self, cmd: Command, now: u32) {
+        let timeout = self.resend_timeout();
+        self.sent.push(SentCommand { cmd, sent_time: now, timeout,
+            resends: 0 });
+    }
+
+    /// Removes the command acknowledged by `channel_id` and `seq_num` and
+    /// feeds the round trip measured via the echoed `send_time` into the
+    /// estimate.
+    pub fn acknowledge(&mut self, channel_id: u8, seq_num: u32, send_time: u32,
+            now: u32) -> Option<Command> {
+        let idx = self.sent.iter().position(|x|
+            x.cmd.header.channel_id == channel_id &&
+            x.cmd.header.reliable_seq_num == seq_num)?;
+        let sent = self.sent.remove(idx);
+
+        let sample = now.wrapping_sub(send_time);
+        if sample < MAX_RESEND_TIMEOUT * 2 {
+            self.update_round_trip_time(sample);
+        }
+
+        Some(sent.cmd)
+    }
+
+    /// Returns the commands whose resend timeout expired and doubles their
+    /// timeout, or `None` once a command ran out of resends.
+    pub fn due(&mut self, now: u32) -> Option<Vec<Command>> {
+        let mut ret = Vec::new();
+        for sent in self.sent.iter_mut() {
+            if now.wrapping_sub(sent.sent_time) < sent.timeout {
+                continue;
+            }
+            if sent.resends == MAX_RESENDS {
+                return None;
+            }
+            sent.sent_time = now;
+            sent.timeout = (sent.timeout * 2).min(MAX_RESEND_TIMEOUT);
+            sent.resends += 1;
+            ret.push(sent.cmd.clone());
+        }
+        Some(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use packets::header::{CommandHeader, FLAG_RELIABLE};
+    use packets::payload::{CommandPayload, Ping};
+    use packets::typ::CommandType;
+
+    use super::*;
+
+    fn cmd(channel_id: u8, seq_num: u32) -> Command {
+        let header = CommandHeader::new(CommandType::Ping, channel_id,
+            FLAG_RELIABLE, seq_num);
+        Command::new(header, CommandPayload::Ping(Ping::new()))
+    }
+
+    #[test]
+    fn round_trip_time_is_smoothed() {
+        let mut queue = ResendQueue::default();
+        queue.push(cmd(0, 1), 0);
+        queue.acknowledge(0, 1, 1000, 1380).unwrap();
+        assert_eq!(queue.round_trip_time(), 310);
+        assert_eq!(queue.round_trip_time_variance(), 133);
+
+        queue.update_round_trip_time(100);
+        assert_eq!(queue.round_trip_time(), 284);
+        assert_eq!(queue.round_trip_time_variance(), 152);
+    }
+
+    #[test]
+    fn resend_timeout_doubles() {
+        let mut queue = ResendQueue::default();
+        queue.push(cmd(0, 1), 0);
+        let mut now = 0;
+        for timeout in [900, 1800, 3600, MAX_RESEND_TIMEOUT,
+                MAX_RESEND_TIMEOUT] {
+            assert!(queue.due(now + timeout - 1).unwrap().is_empty());
+            now += timeout;
+            assert_eq!(queue.due(now).unwrap().len(), 1);
+        }
+    }
+
+    #[test]
+    fn peers_are_lost_after_too_many_resends() {
+        let mut queue = ResendQueue::default();
+        queue.push(cmd(0, 1), 0);
+        let mut now = 0;
+        for _ in 0..MAX_RESENDS {
+            now += MAX_RESEND_TIMEOUT;
+            assert_eq!(queue.due(now).unwrap().len(), 1);
+        }
+        assert!(queue.due(now + MAX_RESEND_TIMEOUT).is_none());
+    }
+
+    #[test]
+    fn acks_match_channel_and_seq_num() {
+        let mut queue = ResendQueue::default();
+        queue.push(cmd(0, 1), 0);
+        queue.push(cmd(1, 1), 0);
+        assert!(queue.acknowledge(1, 2, 0, 10).is_none());
+        assert!(queue.acknowledge(2, 1, 0, 10).is_none());
+
+        let acked = queue.acknowledge(1, 1, 0, 10).unwrap();
+        assert_eq!(acked.header.channel_id, 1);
+        assert_eq!(queue.unacknowledged().collect::<Vec<_>>(), [(0, 1)]);
+        assert!(queue.acknowledge(1, 1, 0, 10).is_none());
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, UdpSocket};
+
+use packets::datagram::Datagram;
+use packets::payload::DisconnectReason;
+use packets::typ::CommandType;
+
+use crate::clock;
+use crate::config::Config;
+use crate::message::Message;
+use crate::peer::{Peer, PeerState};
+use crate::peer_id::{SharedPeerIds, UNASSIGNED_PEER_ID};
+
+#[derive(Debug)]
+pub struct Sessions {
+    peers: BTreeMap<SocketAddr, Peer>,
+    left: Vec<Peer>,
+    peer_ids: SharedPeerIds,
+    /// Largest datagram we take, peers can't use a larger MTU.
+    max_mtu: u16
+}
+
+impl Sessions {
+    pub fn new(config: &Config, peer_ids: SharedPeerIds) -> Self {
+        Self {
+            peers: BTreeMap::new(),
+            left: Vec::new(),
+            peer_ids,
+            max_mtu: config.max_datagram_size.min(u16::MAX as usize) as u16
+        }
+    }
+
+    pub fn get(&self, conn: &SocketAddr) -> Option<&Peer> {
+        self.peers.get(conn)
+    }
+
+    pub fn get_mut(&mut self, conn: &SocketAddr) -> Option<&mut Peer> {
+        self.peers.get_mut(conn)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Processes a datagram of the peer at `conn`. Anything it causes to be
+    /// sent is queued until the next tick, except for turning the peer away.
+    pub fn handle(&mut self, conn: SocketAddr, datagram: Datagram,
+            socket: &UdpSocket) -> Vec<Message> {
+        if !self.peers.contains_key(&conn) {
+            let is_connect = datagram.cmds.iter()
+                .any(|x| x.header.cmd_type == CommandType::Connect);
+            if !is_connect || datagram.peer_id != UNASSIGNED_PEER_ID {
+                println!("Dropping packet from unknown peer {}", conn);
+                return Vec::new();
+            }
+
+            let peer_id = self.peer_ids.lock().unwrap()
+                .allocate(clock::now());
+            let Some(peer_id) = peer_id else {
+                println!("Turning away {}, the server is full", conn);
+                let mut peer = Peer::new(conn, UNASSIGNED_PEER_ID,
+                    datagram.challenge, self.max_mtu);
+                peer.disconnect(DisconnectReason::ServerFull);
+                peer.flush(socket);
+                return Vec::new();
+            };
+            self.peers.insert(conn, Peer::new(conn, peer_id,
+                datagram.challenge, self.max_mtu));
+        }
+
+        let peer = self.peers.get_mut(&conn).unwrap();
+        peer.handle(&datagram)
+    }
+
+    /// Disconnects every peer right away, e.g. when shutting down.
+    pub fn disconnect_all(&mut self, reason: DisconnectReason,
+            socket: &UdpSocket) {
+        for peer in self.peers.values_mut() {
+            peer.disconnect(reason);
+            peer.flush(socket);
+        }
+
+        self.sweep();
+    }
+
+    /// Returns the peers that left since the last call, whether they
+    /// disconnected, timed out or were disconnected by us.
+    pub fn take_left(&mut self) -> Vec<Peer> {
+        std::mem::take(&mut self.left)
+    }
+
+    pub fn tick(&mut self, socket: &UdpSocket, config: &Config) {
+        for peer in self.peers.values_mut() {
+            peer.tick(config);
+            peer.flush(socket);
+        }
+
+        self.sweep();
+    }
+
+    fn sweep(&mut self) {
+        for peer in self.peers.values_mut() {
+            if peer.state == PeerState::Disconnecting {
+                peer.set_state(PeerState::Gone);
+            }
+        }
+        let gone: Vec<SocketAddr> = self.peers.iter()
+            .filter(|(_, x)| x.state == PeerState::Gone)
+            .map(|(conn, _)| *conn)
+            .collect();
+        for conn in gone {
+            let peer = self.peers.remove(&conn).unwrap();
+            self.peer_ids.lock().unwrap().release(peer.peer_id, clock::now());
+            self.left.push(peer);
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use std::io;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config::{Endpoint, Transport};
+
+/// Opens the socket of an endpoint. IPv6 sockets only take IPv6 clients, so
+/// they behave the same whatever the OS defaults to and don't clash with an
+/// IPv4 socket on the same port.
+pub fn bind(endpoint: &Endpoint) -> io::Result<Socket> {
+    let (typ, protocol) = match endpoint.transport {
+        Transport::Udp => (Type::DGRAM, Protocol::UDP),
+        Transport::Tcp => (Type::STREAM, Protocol::TCP)
+    };
+    let socket = Socket::new(Domain::for_address(endpoint.addr), typ,
+        Some(protocol))?;
+    if endpoint.addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if endpoint.transport == Transport::Tcp {
+        // Like std does, so a restart doesn't wait for old connections.
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&endpoint.addr.into())?;
+    if endpoint.transport == Transport::Tcp {
+        socket.listen(128)?;
+    }
+    Ok(socket)
+}
@@ -0,0 +1,15 @@
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub received: u64,
+    pub oversized: u64,
+    pub truncated: u64,
+    /// Datagrams that were complete but couldn't be decoded.
+    pub malformed: u64,
+    /// Datagrams whose CRC didn't match their content.
+    pub bad_crc: u64,
+    /// Encrypted datagrams that failed authentication or decryption.
+    pub undecryptable: u64,
+    /// Datagrams whose challenge or peer id didn't match the peer at their
+    /// address.
+    pub rejected: u64
+}
@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{self, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use packets::error::EncodeError;
+use packets::photon::PhotonCommand;
+use packets::tcp::{Frame, Ping, Sender};
+
+use crate::clock;
+use crate::config::{Config, Endpoint, Role};
+use crate::encryption::CryptoError;
+use crate::message::{Message, Messages, Received};
+use crate::peer_id::SharedPeerIds;
+use crate::socket;
+use crate::stats::Stats;
+
+/// A client that fell back to TCP. Its frames are handled as soon as they're
+/// complete, the stream is all the reliability there is.
+#[derive(Debug)]
+pub struct TcpConnection {
+    pub addr: SocketAddr,
+    /// Never sent over TCP, it only counts the connection towards the peer
+    /// limit shared with the other listeners.
+    peer_id: u16,
+    stream: TcpStream,
+    pub messages: Messages,
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+    last_received: u32,
+    closed: bool
+}
+
+impl TcpConnection {
+    fn new(stream: TcpStream, addr: SocketAddr, peer_id: u16)
+            -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            addr,
+            peer_id,
+            stream,
+            messages: Messages::default(),
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+            last_received: clock::now(),
+            closed: false
+        })
+    }
+
+    /// Reads what arrived and returns the messages that are ready for the
+    /// application.
+    fn receive(&mut self, config: &Config, stats: &mut Stats) -> Vec<Message> {
+        let mut buf = [0; 0x1000];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                },
+                Ok(amt) => self.incoming.extend_from_slice(&buf[..amt]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => {
+                    println!("Failed to receive from {}: {}", self.addr, e);
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+
+        let mut delivered = Vec::new();
+        while !self.closed {
+            let len = match Frame::len(&self.incoming, Sender::Client) {
+                Ok(Some(len)) => len,
+                Ok(None) => break,
+                Err(e) => {
+                    // There's no telling where the next frame starts.
+                    stats.malformed += 1;
+                    println!("Closing {} after a malformed frame: {:?} ({} so \
+                        far)", self.addr, e, stats.malformed);
+                    self.closed = true;
+                    break;
+                }
+            };
+            if len > config.max_frame_size {
+                stats.oversized += 1;
+                println!("Closing {} after an oversized frame ({} so far)",
+                    self.addr, stats.oversized);
+                self.closed = true;
+                break;
+            }
+            if self.incoming.len() < len {
+                break;
+            }
+
+            stats.received += 1;
+            self.last_received = clock::now();
+            let frame = Frame::deserialize(&self.incoming[..len],
+                Sender::Client);
+            self.incoming.drain(..len);
+            match frame {
+                Ok(frame) => self.dispatch(frame, &mut delivered),
+                Err(e) => {
+                    stats.malformed += 1;
+                    println!("Dropping malformed frame from {}: {:?} ({} so \
+                        far)", self.addr, e, stats.malformed);
+                }
+            }
+        }
+        delivered
+    }
+
+    fn dispatch(&mut self, frame: Frame, delivered: &mut Vec<Message>) {
+        match frame {
+            Frame::Ping(ping) => {
+                let pong = Ping { server_time: Some(clock::now()),
+                    client_time: ping.client_time };
+                self.outgoing.extend_from_slice(&pong.serialize());
+            },
+            Frame::Message { channel_id, msg, .. } => {
+                match self.messages.receive(channel_id, msg) {
+                    Ok(Received::Deliver(msg)) => delivered.push(msg),
+                    // There are no datagrams to encrypt over TCP, the secrets
+                    // just go unused.
+                    Ok(Received::Reply(reply) |
+                            Received::EnableEncryption { reply, .. }) => {
+                        if let Err(e) = self.send_message(channel_id, reply) {
+                            println!("{}: failed to reply: {:?}", self.addr, e);
+                        }
+                    },
+                    Err(e) => println!("{}: dropping encrypted message: {:?}",
+                        self.addr, e)
+                }
+            }
+        }
+    }
+
+    pub fn send_message(&mut self, channel_id: u8, msg: PhotonCommand)
+            -> Result<(), EncodeError> {
+        let frame = Frame::Message { channel_id, reliable: true, msg };
+        self.outgoing.extend_from_slice(&frame.serialize()?);
+        Ok(())
+    }
+
+    /// Answers one of the peer's messages, see `Messages::reply`.
+    pub fn reply(&mut self, to: &Message, msg: PhotonCommand)
+            -> Result<(), CryptoError> {
+        let msg = self.messages.reply(to, msg)?;
+        self.send_message(to.channel_id, msg)
+            .map_err(CryptoError::Unencodable)
+    }
+
+    /// Writes as much of what's queued as the socket takes.
+    fn flush(&mut self) {
+        while !self.outgoing.is_empty() && !self.closed {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => self.closed = true,
+                Ok(amt) => {
+                    self.outgoing.drain(..amt);
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => {
+                    println!("Failed to send to {}: {}", self.addr, e);
+                    self.closed = true;
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self, config: &Config) {
+        let silent = clock::now().wrapping_sub(self.last_received);
+        if silent as u128 > config.peer_timeout.as_millis() {
+            println!("{} timed out", self.addr);
+            self.closed = true;
+        }
+    }
+}
+
+pub struct TcpListener {
+    endpoint: Endpoint,
+    listener: net::TcpListener,
+    config: Config,
+    connections: BTreeMap<SocketAddr, TcpConnection>,
+    peer_ids: SharedPeerIds,
+    stats: Stats
+}
+
+impl TcpListener {
+    pub fn bind(endpoint: Endpoint, config: Config, peer_ids: SharedPeerIds)
+            -> io::Result<Self> {
+        let listener = net::TcpListener::from(socket::bind(&endpoint)?);
+        listener.set_nonblocking(true)?;
+        clock::start();
+        Ok(Self {
+            endpoint,
+            listener,
+            config,
+            connections: BTreeMap::new(),
+            peer_ids,
+            stats: Stats::default()
+        })
+    }
+
+    pub fn role(&self) -> Role {
+        self.endpoint.role
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    pub fn get_mut(&mut self, addr: &SocketAddr)
+            -> Option<&mut TcpConnection> {
+        self.connections.get_mut(addr)
+    }
+
+    /// Serves clients until `stop` is set, then sends what's still queued
+    /// for them and closes their connections.
+    pub fn run(&mut self, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            self.accept();
+            for conn in self.connections.values_mut() {
+                for msg in conn.receive(&self.config, &mut self.stats) {
+                    println!("{} => {:?}", conn.addr, msg);
+                }
+                conn.tick(&self.config);
+                conn.flush();
+            }
+            let peer_ids = &self.peer_ids;
+            self.connections.retain(|addr, conn| {
+                if conn.closed {
+                    println!("tcp peer {} left", addr);
+                    peer_ids.lock().unwrap()
+                        .release(conn.peer_id, clock::now());
+                }
+                !conn.closed
+            });
+            thread::sleep(self.config.tick);
+        }
+        let mut peer_ids = self.peer_ids.lock().unwrap();
+        for conn in self.connections.values_mut() {
+            conn.flush();
+            peer_ids.release(conn.peer_id, clock::now());
+        }
+        self.connections.clear();
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let (stream, addr) = match self.listener.accept() {
+                Ok(x) => x,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    println!("Failed to accept: {}", e);
+                    return;
+                }
+            };
+            // There's nothing like Photon's ServerFull over TCP, dropping the
+            // stream closes it.
+            let peer_id = self.peer_ids.lock().unwrap()
+                .allocate(clock::now());
+            let Some(peer_id) = peer_id else {
+                println!("Turning away {}, the server is full", addr);
+                continue;
+            };
+            match TcpConnection::new(stream, addr, peer_id) {
+                Ok(conn) => {
+                    println!("tcp peer {} connected", addr);
+                    self.connections.insert(addr, conn);
+                },
+                Err(e) => {
+                    println!("Failed to set up {}: {}", addr, e);
+                    self.peer_ids.lock().unwrap()
+                        .release(peer_id, clock::now());
+                }
+            }
+        }
+    }
+}
@@ -1,8 +1,16 @@
-use std::net::UdpSocket;
-use std::env::args;
+use std::env::{self, args};
 use std::process::exit;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
 
-use server::{handle_request, parse_packets};
+use server::{parse_packets, Config, Endpoint, Listener, PeerIds,
+    TcpListener, Transport};
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+/// Comma separated `<role>[/tcp]=<address>` pairs to listen on instead of the
+/// default Photon ports.
+const ENDPOINTS_VAR: &str = "GLUON_ENDPOINTS";
 
 fn main() {
     if args().len() > 1 && args().len() == 3 {
@@ -13,14 +21,60 @@ fn main() {
         exit(1);
     }
 
-    let socket = UdpSocket::bind("0.0.0.0:5055").unwrap();
+    let mut config = Config::default();
+    let configured = env::var(ENDPOINTS_VAR).is_ok();
+    if let Ok(endpoints) = env::var(ENDPOINTS_VAR) {
+        config.endpoints = match endpoints.split(',')
+                .map(|x| x.trim().parse::<Endpoint>())
+                .collect::<Result<_, _>>() {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Invalid {}: {}", ENDPOINTS_VAR, e);
+                exit(1);
+            }
+        };
+    }
 
-    loop {
-        let mut buf = [0; 0x38];
-        let (amt, conn) = socket.recv_from(&mut buf).unwrap();
+    // Listeners stop once this is set, which lets them say goodbye to their
+    // peers before gluon exits.
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        if let Err(e) = signal_hook::flag::register(signal,
+                Arc::clone(&stop)) {
+            println!("Failed to handle signal {}: {}", signal, e);
+            exit(1);
+        }
+    }
 
-        if amt == 0x38 {
-            handle_request(&buf, &socket, conn);
+    let peer_ids = PeerIds::shared(config.max_peers);
+    let mut threads = Vec::new();
+    for endpoint in config.endpoints.clone() {
+        let stop = Arc::clone(&stop);
+        let peer_ids = Arc::clone(&peer_ids);
+        let bound = match endpoint.transport {
+            Transport::Udp => Listener::bind(endpoint, config.clone(), peer_ids)
+                .map(|mut x| thread::spawn(move || x.run(&stop))),
+            Transport::Tcp => TcpListener::bind(endpoint, config.clone(),
+                    peer_ids)
+                .map(|mut x| thread::spawn(move || x.run(&stop)))
+        };
+        match bound {
+            Ok(thread) => threads.push(thread),
+            // Not every host has IPv6, the default IPv4 endpoints still work
+            // without it.
+            Err(e) if !configured && endpoint.addr.is_ipv6() => {
+                println!("Skipping {:?}: {}", endpoint, e);
+                continue;
+            },
+            Err(e) => {
+                println!("Failed to bind {:?}: {}", endpoint, e);
+                exit(1);
+            }
         }
+        println!("Listening on {} ({:?}) as {:?}", endpoint.addr,
+            endpoint.transport, endpoint.role);
+    }
+    for thread in threads {
+        thread.join().unwrap();
     }
 }